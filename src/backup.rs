@@ -0,0 +1,221 @@
+/*
+ * Versioned backups of encrypted module files. Before a module file is overwritten its previous
+ * content is copied to backups/<module>/<timestamp>.json in the pwmanager directory. The
+ * timestamps are UTC, so the backups of a module sort chronologically by name.
+ *
+ * Retention: the newest KEEP_LAST backups are kept, and of the older ones the newest backup of
+ * each day within the last KEEP_DAYS days. Everything else is removed.
+ * */
+
+use std::collections::HashSet;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::payload;
+use crate::store::VaultStore;
+
+/* Directory of the backups in the pwmanager directory */
+pub const BACKUP_DIR: &str = "backups";
+/* Number of most recent backups that are always kept */
+pub const KEEP_LAST: usize = 10;
+/* Number of days for which one backup per day is kept */
+pub const KEEP_DAYS: i64 = 30;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/* Directory of the backups of the module name */
+fn backup_dir(name: &str) -> String {
+    format!("{}/{}", BACKUP_DIR, name)
+}
+
+/* Copies the module file to the backups of the module name and prunes them. Does nothing if
+ * there is no module file yet or if it is plaintext, so backups never hold unencrypted entries */
+pub fn backup_module(store: &dyn VaultStore, name: &str, file: &str) -> io::Result<()> {
+    let content = match store.read(file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if payload::is_plaintext(&content) {
+        return Ok(());
+    }
+    let dir = backup_dir(name);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let stamp = timestamp(now.as_secs() as i64, now.subsec_millis());
+    store.write(&format!("{}/{}.json", dir, stamp), &content)?;
+    prune(store, &dir, now.as_secs() as i64)
+}
+
+/* Moves the backups of a module that is stored under a new name. They stay encrypted for the
+ * old name, so they have to be restored under it */
+pub fn rename_backups(store: &dyn VaultStore, from: &str, to: &str) -> io::Result<()> {
+    match store.rename(&backup_dir(from), &backup_dir(to)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/* Removes the backups in dir that the retention policy doesn't keep */
+fn prune(store: &dyn VaultStore, dir: &str, now: i64) -> io::Result<()> {
+    let mut backups = Vec::new();
+    for file in store.list(dir)? {
+        let day = file.strip_suffix(".json").and_then(day_of_timestamp);
+        // Files that aren't backups are left alone
+        if let Some(day) = day {
+            backups.push((file, day));
+        }
+    }
+    backups.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+    let today = now.div_euclid(SECS_PER_DAY);
+    let mut kept_days = HashSet::new();
+    for (i, (file, day)) in backups.iter().enumerate() {
+        if i < KEEP_LAST || (today - day < KEEP_DAYS && !kept_days.contains(day)) {
+            kept_days.insert(*day);
+        } else {
+            store.remove(&format!("{}/{}", dir, file))?;
+        }
+    }
+    Ok(())
+}
+
+/* Formats seconds since the epoch as e.g. 2022-11-05T14-03-09.123Z. Colons are avoided since
+ * they aren't allowed in file names on Windows */
+fn timestamp(secs: i64, millis: u32) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+    let secs_of_day = secs.rem_euclid(SECS_PER_DAY);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}-{:02}-{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        millis
+    )
+}
+
+/* Days since the epoch of the date a backup was made, parsed from its file name */
+fn day_of_timestamp(stem: &str) -> Option<i64> {
+    let date = stem.get(..10)?;
+    let mut parts = date.split('-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
+
+/* Converts days since the epoch to a proleptic Gregorian date (Howard Hinnant's algorithm) */
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/* Converts a proleptic Gregorian date to days since the epoch */
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    const DIR: &str = "backups/test";
+
+    fn add_backup(store: &MemoryStore, secs: i64) {
+        store
+            .write(&format!("{}/{}.json", DIR, timestamp(secs, 0)), b"backup")
+            .unwrap();
+    }
+
+    #[test]
+    fn timestamp_formats_dates() {
+        assert_eq!(timestamp(0, 0), "1970-01-01T00-00-00.000Z");
+        assert_eq!(timestamp(1667656989, 123), "2022-11-05T14-03-09.123Z");
+        assert_eq!(timestamp(951782400, 7), "2000-02-29T00-00-00.007Z");
+        assert_eq!(timestamp(-1, 999), "1969-12-31T23-59-59.999Z");
+    }
+
+    #[test]
+    fn day_of_timestamp_reads_back_the_date() {
+        for secs in [0, 951782400, 1667656989, 4102444799, -86400] {
+            let stem = timestamp(secs, 0);
+            assert_eq!(day_of_timestamp(&stem), Some(secs.div_euclid(SECS_PER_DAY)));
+        }
+        assert_eq!(day_of_timestamp("2022-13-05T14-03-09.123Z"), None);
+        assert_eq!(day_of_timestamp("2022-11-00T14-03-09.123Z"), None);
+        assert_eq!(day_of_timestamp("2022-11-5"), None);
+        assert_eq!(day_of_timestamp("notes"), None);
+        assert_eq!(day_of_timestamp("20221105T14-03-09.123Z"), None);
+    }
+
+    #[test]
+    fn prune_keeps_the_last_backups() {
+        let store = MemoryStore::new();
+        let now = 1667656989;
+        for i in 0..15 {
+            add_backup(&store, now - 15 + i);
+        }
+        prune(&store, DIR, now).unwrap();
+        let mut kept = store.list(DIR).unwrap();
+        kept.sort_unstable();
+        let expected: Vec<String> = (5..15)
+            .map(|i| format!("{}.json", timestamp(now - 15 + i, 0)))
+            .collect();
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn prune_keeps_one_backup_per_recent_day() {
+        let store = MemoryStore::new();
+        let now = 1667656989;
+        // Two backups on each of the last 40 days, followed by the latest ones of today
+        for day in 1..=40 {
+            add_backup(&store, now - day * SECS_PER_DAY);
+            add_backup(&store, now - day * SECS_PER_DAY + 60);
+        }
+        for i in 0..KEEP_LAST as i64 {
+            add_backup(&store, now - 100 + i);
+        }
+        store
+            .write(&format!("{}/notes.txt", DIR), b"notes")
+            .unwrap();
+        prune(&store, DIR, now).unwrap();
+
+        let kept = store.list(DIR).unwrap();
+        assert!(kept.contains(&"notes.txt".to_owned()));
+        let days: Vec<i64> = kept
+            .iter()
+            .filter_map(|f| f.strip_suffix(".json").and_then(day_of_timestamp))
+            .collect();
+        let today = now.div_euclid(SECS_PER_DAY);
+        assert_eq!(days.len(), KEEP_LAST + KEEP_DAYS as usize - 1);
+        for day in today - KEEP_DAYS + 1..today {
+            assert_eq!(days.iter().filter(|&&d| d == day).count(), 1);
+        }
+        assert!(days.iter().all(|&d| today - d < KEEP_DAYS));
+        // The later backup of a day is the one kept
+        assert!(kept.contains(&format!("{}.json", timestamp(now - SECS_PER_DAY + 60, 0))));
+    }
+}
@@ -0,0 +1,121 @@
+/*
+ * AEAD cipher suites modules can be encrypted with. The suite of a module is recorded in its
+ * scheme, so modules using different suites can live in the same vault.
+ * */
+
+use std::{fmt, marker::PhantomData};
+
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::{
+    aead::{
+        generic_array::{typenum::Unsigned, GenericArray},
+        Aead, AeadCore, Payload,
+    },
+    consts::U32,
+    ChaCha20Poly1305, KeyInit, KeySizeUser, XChaCha20Poly1305,
+};
+
+use crate::error::{Error, Result};
+
+/* Minimal interface of an AEAD cipher with a 256 bit key */
+pub trait Cipher: Sync {
+    /* Length of the nonce in bytes */
+    fn nonce_len(&self) -> usize;
+
+    fn encrypt(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+
+    fn decrypt(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+}
+
+/* Implements Cipher for an AEAD of the RustCrypto crates */
+struct RustCrypto<A>(PhantomData<fn() -> A>);
+
+impl<A> Cipher for RustCrypto<A>
+where
+    A: Aead + AeadCore + KeyInit + KeySizeUser<KeySize = U32>,
+{
+    fn nonce_len(&self) -> usize {
+        A::NonceSize::USIZE
+    }
+
+    fn encrypt(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = A::new_from_slice(key).map_err(|e| Error::invalid(e.to_string()))?;
+        if nonce.len() != self.nonce_len() {
+            return Err(Error::invalid("Nonce has the wrong length"));
+        }
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|e| Error::invalid(e.to_string()))?;
+        Ok(ciphertext)
+    }
+
+    fn decrypt(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = A::new_from_slice(key).map_err(|e| Error::invalid(e.to_string()))?;
+        if nonce.len() != self.nonce_len() {
+            return Err(Error::invalid("Nonce has the wrong length"));
+        }
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        // Callers that decrypt with a key derived from a password report this as wrong password
+        let plaintext = cipher
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .map_err(|_| Error::corrupt("Authentication failed"))?;
+        Ok(plaintext)
+    }
+}
+
+static CHACHA20_POLY1305: RustCrypto<ChaCha20Poly1305> = RustCrypto(PhantomData);
+static XCHACHA20_POLY1305: RustCrypto<XChaCha20Poly1305> = RustCrypto(PhantomData);
+static AES_256_GCM_SIV: RustCrypto<Aes256GcmSiv> = RustCrypto(PhantomData);
+
+/* The suites a scheme can record */
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CipherSuite {
+    /* Used by every module written before suites were recorded. Its 12 byte random nonces are
+     * only safe for a limited number of encryptions, so it is not used for new modules */
+    ChaCha20Poly1305,
+    #[default]
+    XChaCha20Poly1305,
+    Aes256GcmSiv,
+}
+
+impl CipherSuite {
+    pub fn cipher(&self) -> &'static dyn Cipher {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+            CipherSuite::XChaCha20Poly1305 => &XCHACHA20_POLY1305,
+            CipherSuite::Aes256GcmSiv => &AES_256_GCM_SIV,
+        }
+    }
+
+    /* Name of the suite in the module header */
+    pub fn name(&self) -> &'static str {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => "chacha20poly1305",
+            CipherSuite::XChaCha20Poly1305 => "xchacha20poly1305",
+            CipherSuite::Aes256GcmSiv => "aes256gcmsiv",
+        }
+    }
+
+    /* Parses the name of a suite. Short names are accepted for typing them in the UI */
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chacha20poly1305" | "chacha" => Some(CipherSuite::ChaCha20Poly1305),
+            "xchacha20poly1305" | "xchacha" => Some(CipherSuite::XChaCha20Poly1305),
+            "aes256gcmsiv" | "aes" => Some(CipherSuite::Aes256GcmSiv),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,244 @@
+/*
+ * Layout of an encrypted module file:
+ * magic (4 bytes) | format version (u16 LE) | header length (u32 LE) | header | ciphertext
+ * The header is the RON serialized EncryptionScheme holding the Argon2 PHC parameters and the
+ * salt. The ciphertext starts with the nonce it was encrypted with.
+ *
+ * The AAD is everything before the ciphertext followed by the module name, so a vault can be
+ * moved while swapping ciphertext or headers between modules is still detected.
+ * */
+
+use crate::error::{Error, Result};
+use crate::password::PasswordEntries;
+use crate::payload;
+use crate::pbes::{EncryptionScheme, Key};
+
+/* Magic number at the start of every encrypted module file */
+pub const MAGIC: &[u8; 4] = b"PWMT";
+/* Version of the container format written by this build */
+pub const FORMAT_VERSION: u16 = 1;
+
+const PREFIX_LEN: usize = MAGIC.len() + 2 + 4;
+
+/* An encrypted module split into its parts */
+pub struct Container<'a> {
+    pub scheme: EncryptionScheme<'static>,
+    /* Everything before the ciphertext */
+    pub header: &'a [u8],
+    pub ciphertext: &'a [u8],
+}
+
+/* Checks if the content of a module file starts with the container magic number */
+pub fn has_header(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+/* Builds everything that precedes the ciphertext in a module file */
+pub fn encode_header(scheme: &EncryptionScheme) -> Result<Vec<u8>> {
+    let header = ron::ser::to_string(scheme)?;
+    let header_len =
+        u32::try_from(header.len()).map_err(|_| Error::invalid("Module header too long"))?;
+
+    let mut content = Vec::with_capacity(PREFIX_LEN + header.len());
+    content.extend_from_slice(MAGIC);
+    content.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    content.extend_from_slice(&header_len.to_le_bytes());
+    content.extend_from_slice(header.as_bytes());
+    Ok(content)
+}
+
+/* AAD binding a ciphertext to its header and to the logical name of its module */
+pub fn aad(header: &[u8], name: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + name.len());
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(name.as_bytes());
+    aad
+}
+
+/* Encrypts the entries of the module name with its data key and returns the content of the
+ * module file. The scheme has to hold the wrapped data key, see unlock_or_generate */
+pub fn seal(
+    scheme: &mut EncryptionScheme,
+    key: &Key,
+    name: &str,
+    entries: &PasswordEntries,
+) -> Result<Vec<u8>> {
+    let plaintext = payload::encode(entries)?;
+    seal_bytes(scheme, key, name, &plaintext)
+}
+
+/* Encrypts plaintext bound to name and returns the content of the file */
+pub fn seal_bytes(
+    scheme: &mut EncryptionScheme,
+    key: &Key,
+    name: &str,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    if scheme.wrapped_key.is_none() {
+        return Err(Error::invalid("Scheme has no wrapped data key"));
+    }
+    // Containers carry the nonce with the ciphertext, so a legacy shared nonce is dropped
+    // before it ends up in the header
+    scheme.nonce = None;
+    let mut content = encode_header(scheme)?;
+    let ciphertext = scheme.encrypt_with_key(key, plaintext, &aad(&content, name))?;
+    content.extend_from_slice(&ciphertext);
+    Ok(content)
+}
+
+/* Parses the content of a module file. Fails if the file has no header, is truncated or was
+ * written by a newer version of the format */
+pub fn decode(content: &[u8]) -> Result<Container<'_>> {
+    if !has_header(content) {
+        return Err(Error::corrupt("Missing module header"));
+    }
+    if content.len() < PREFIX_LEN {
+        return Err(Error::corrupt("Truncated module header"));
+    }
+    let version = u16::from_le_bytes([content[4], content[5]]);
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let header_len = u32::from_le_bytes([content[6], content[7], content[8], content[9]]) as usize;
+    // The length comes from the file, adding it to PREFIX_LEN could overflow
+    let header = content[PREFIX_LEN..]
+        .get(..header_len)
+        .ok_or_else(|| Error::corrupt("Truncated module header"))?;
+    let scheme: EncryptionScheme = ron::de::from_bytes(header)?;
+
+    let (header, ciphertext) = content.split_at(PREFIX_LEN + header_len);
+    Ok(Container {
+        scheme,
+        header,
+        ciphertext,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::cipher::CipherSuite;
+    use crate::password::Password;
+
+    fn sealed_module() -> (Vec<u8>, Key) {
+        let mut ec = EncryptionScheme::with_params(8, 1, 1).unwrap();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        let mut entries = PasswordEntries::new();
+        entries.insert(Cow::from("mail"), Password::new_from("secret"));
+        (seal(&mut ec, &key, "Private", &entries).unwrap(), key)
+    }
+
+    fn open(content: &[u8], key: &Key) -> Result<PasswordEntries<'static>> {
+        open_as(content, key, "Private")
+    }
+
+    fn open_as(content: &[u8], key: &Key, name: &str) -> Result<PasswordEntries<'static>> {
+        let c = decode(content)?;
+        c.scheme
+            .decrypt_entries(key, c.ciphertext, &aad(c.header, name))
+    }
+
+    #[test]
+    fn every_suite_round_trips() {
+        let suites = [
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::Aes256GcmSiv,
+        ];
+        let mut entries = PasswordEntries::new();
+        entries.insert(Cow::from("mail"), Password::new_from("secret"));
+        for suite in suites {
+            let mut ec = EncryptionScheme::with_params(8, 1, 1).unwrap();
+            ec.suite = suite;
+            let key = ec.unlock_or_generate("password", None).unwrap();
+            let content = seal(&mut ec, &key, "Private", &entries).unwrap();
+
+            let c = decode(&content).unwrap();
+            assert_eq!(c.scheme.suite, suite);
+            assert_eq!(c.scheme.unlock("password", None).unwrap(), key);
+            assert_eq!(open(&content, &key).unwrap(), entries);
+            let mut tampered = content.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(open(&tampered, &key).is_err(), "{}", suite);
+        }
+    }
+
+    #[test]
+    fn swapped_parts_are_rejected() {
+        // Both modules share the data key, only the binding tells them apart
+        let (private, key) = sealed_module();
+        let mut ec = EncryptionScheme::with_params(8, 1, 1).unwrap();
+        ec.rewrap(&key, "password", None).unwrap();
+        let mut entries = PasswordEntries::new();
+        entries.insert(Cow::from("bank"), Password::new_from("other"));
+        let work = seal(&mut ec, &key, "Work", &entries).unwrap();
+        open_as(&work, &key, "Work").unwrap();
+
+        let split = |content: &[u8]| {
+            let c = decode(content).unwrap();
+            (c.header.to_vec(), c.ciphertext.to_vec())
+        };
+        let (private_header, private_ciphertext) = split(&private);
+        let (work_header, work_ciphertext) = split(&work);
+        assert_ne!(private_header, work_header);
+
+        let swapped_ciphertext = [private_header.as_slice(), &work_ciphertext].concat();
+        assert!(open_as(&swapped_ciphertext, &key, "Private").is_err());
+        assert!(open_as(&swapped_ciphertext, &key, "Work").is_err());
+        let swapped_header = [work_header.as_slice(), &private_ciphertext].concat();
+        assert!(open_as(&swapped_header, &key, "Private").is_err());
+        assert!(open_as(&swapped_header, &key, "Work").is_err());
+        // A whole file copied over another module is rejected too
+        assert!(open_as(&work, &key, "Private").is_err());
+    }
+
+    #[test]
+    fn truncated_module_is_rejected() {
+        let (content, key) = sealed_module();
+        open(&content, &key).unwrap();
+        for len in 0..content.len() {
+            assert!(open(&content[..len], &key).is_err(), "truncated to {}", len);
+        }
+    }
+
+    #[test]
+    fn bit_flipped_module_is_rejected() {
+        let (content, key) = sealed_module();
+        for i in 0..content.len() {
+            for bit in 0..8 {
+                let mut flipped = content.clone();
+                flipped[i] ^= 1 << bit;
+                assert!(
+                    open(&flipped, &key).is_err(),
+                    "flipped bit {} of byte {}",
+                    bit,
+                    i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn random_module_is_rejected() {
+        let (_, key) = sealed_module();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            let mut content = vec![0u8; rng.gen_range(0..256)];
+            rng.fill(content.as_mut_slice());
+            // Half of them get a valid prefix so the header length and header are parsed
+            if rng.gen() {
+                let mut prefixed = MAGIC.to_vec();
+                prefixed.extend_from_slice(&rng.gen_range(0..=FORMAT_VERSION + 1).to_le_bytes());
+                prefixed.extend_from_slice(&rng.gen_range(0..300u32).to_le_bytes());
+                prefixed.extend_from_slice(&content);
+                content = prefixed;
+            }
+            assert!(open(&content, &key).is_err());
+        }
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * Errors of the vault, crypto and UI layers. The variants separate the cases the UI reacts to
+ * differently, e.g. a wrong password is asked for again while a corrupt file is only reported.
+ * */
+
+use std::{env, fmt, io};
+
+use crate::payload::PAYLOAD_VERSION;
+
+#[derive(Debug)]
+pub enum Error {
+    /* The password did not unlock the key, i.e. authentication of the wrapped key failed */
+    WrongPassword,
+    /* The keyfile is not the one the module was encrypted with */
+    WrongKeyfile,
+    /* The module requires a keyfile but none was given */
+    KeyfileRequired,
+    /* A vault file can't be parsed or its content failed authentication with the right key */
+    Corrupt(String),
+    /* The file was written by a newer version of the format */
+    UnsupportedVersion(u16),
+    /* The module payload was written by a newer version of its schema */
+    UnsupportedPayloadVersion(u32),
+    Io(io::Error),
+    ModuleNotFound(String),
+    ModuleExists(String),
+    /* The vault is locked by another running instance */
+    Locked { pid: u32, host: String },
+    /* Input that can't be used, e.g. invalid options or an action not possible for a module */
+    Invalid(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn corrupt(message: impl Into<String>) -> Self {
+        Error::Corrupt(message.into())
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Error::Invalid(message.into())
+    }
+
+    /* Checks if the password or keyfile was wrong, so the user can simply try again */
+    pub fn is_wrong_credentials(&self) -> bool {
+        matches!(
+            self,
+            Error::WrongPassword | Error::WrongKeyfile | Error::KeyfileRequired
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::WrongPassword => f.write_str("Wrong password"),
+            Error::WrongKeyfile => f.write_str("Wrong keyfile"),
+            Error::KeyfileRequired => f.write_str("This module requires a keyfile"),
+            Error::Corrupt(message) => write!(f, "Corrupt file: {}", message),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported format version {}", version)
+            }
+            Error::UnsupportedPayloadVersion(version) => write!(
+                f,
+                "Module was saved by a newer pwmanager (payload version {}, this one reads up \
+                 to {}). Update pwmanager to open it",
+                version, PAYLOAD_VERSION
+            ),
+            Error::Io(e) => e.fmt(f),
+            Error::ModuleNotFound(name) => write!(f, "Module {} not found", name),
+            Error::ModuleExists(name) => write!(f, "Module {} already exists", name),
+            Error::Locked { pid, host } => write!(
+                f,
+                "The vault is in use by another pwmanager (PID {} on host {})",
+                pid, host
+            ),
+            Error::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Corrupt(e.to_string())
+        }
+    }
+}
+
+impl From<ron::de::SpannedError> for Error {
+    fn from(e: ron::de::SpannedError) -> Self {
+        Error::Corrupt(e.to_string())
+    }
+}
+
+/* Only serializing with ron fails with this error */
+impl From<ron::Error> for Error {
+    fn from(e: ron::Error) -> Self {
+        Error::Invalid(e.to_string())
+    }
+}
+
+impl From<env::VarError> for Error {
+    fn from(e: env::VarError) -> Self {
+        Error::Invalid(format!("Home directory not found: {}", e))
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Vault keyring. Holds the data keys of the modules that are unlocked together with a single
+ * master password. The keyring is stored in the container format like a module, so its data key
+ * is wrapped by the key derived from the master password.
+ *
+ * Modules keep their own password when they join the keyring. A module that is not in the
+ * keyring can only be unlocked with its own password.
+ *
+ * The keyring also holds the index of hidden modules, which maps the random IDs their files are
+ * named after to their real names. It is stored in its own file encrypted with the keyring's
+ * data key, so the master password unlocks both.
+ * */
+
+use std::collections::HashMap;
+use std::io;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::container;
+use crate::error::Result;
+use crate::pbes::{EncryptionScheme, Key, KEY_LEN};
+use crate::store::VaultStore;
+
+/* File of the keyring in the pwmanager directory */
+pub const KEYRING_FILE: &str = "keyring.pwm";
+/* File of the index of hidden modules in the pwmanager directory */
+pub const INDEX_FILE: &str = "index.pwm";
+
+pub struct Keyring {
+    scheme: EncryptionScheme<'static>,
+    key: Key,
+    keys: HashMap<String, Key>,
+    /* Real names of the hidden modules by their ID */
+    names: HashMap<String, String>,
+}
+
+impl Keyring {
+    /* Checks if the vault has a keyring */
+    pub fn exists(store: &dyn VaultStore) -> Result<bool> {
+        Ok(store.exists(KEYRING_FILE)?)
+    }
+
+    /* Creates an empty keyring protected by the master password and writes it */
+    pub fn create(
+        store: &dyn VaultStore,
+        master_password: &str,
+        mut scheme: EncryptionScheme<'static>,
+    ) -> Result<Self> {
+        let key = scheme.unlock_or_generate(master_password, None)?;
+        let mut keyring = Self {
+            scheme,
+            key,
+            keys: HashMap::new(),
+            names: HashMap::new(),
+        };
+        keyring.write(store)?;
+        Ok(keyring)
+    }
+
+    /* Reads and decrypts the keyring with the master password */
+    pub fn unlock(store: &dyn VaultStore, master_password: &str) -> Result<Self> {
+        let content = store.read(KEYRING_FILE)?;
+        let c = container::decode(&content)?;
+        let key = c.scheme.unlock(master_password, None)?;
+        let plaintext = Zeroizing::new(c.scheme.decrypt_with_key(
+            &key,
+            c.ciphertext,
+            &container::aad(c.header, KEYRING_FILE),
+        )?);
+        let mut stored: HashMap<String, [u8; KEY_LEN]> = serde_json::from_slice(&plaintext)?;
+        let keys = stored
+            .iter_mut()
+            .map(|(name, k)| {
+                let module_key = Key::new(*k);
+                k.zeroize();
+                (name.clone(), module_key)
+            })
+            .collect();
+        let names = Self::read_index(store, &key)?;
+        Ok(Self {
+            scheme: c.scheme,
+            key,
+            keys,
+            names,
+        })
+    }
+
+    /* Reads the index of hidden modules. A vault without hidden modules has no index */
+    fn read_index(store: &dyn VaultStore, key: &Key) -> Result<HashMap<String, String>> {
+        let content = match store.read(INDEX_FILE) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let c = container::decode(&content)?;
+        let plaintext = Zeroizing::new(c.scheme.decrypt_with_key(
+            key,
+            c.ciphertext,
+            &container::aad(c.header, INDEX_FILE),
+        )?);
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /* Encrypts the keyring with its data key and writes it */
+    pub fn write(&mut self, store: &dyn VaultStore) -> Result<()> {
+        let stored: HashMap<&str, &[u8; KEY_LEN]> = self
+            .keys
+            .iter()
+            .map(|(name, key)| (name.as_str(), &**key))
+            .collect();
+        let plaintext = Zeroizing::new(serde_json::to_vec(&stored)?);
+        let content = container::seal_bytes(&mut self.scheme, &self.key, KEYRING_FILE, &plaintext)?;
+        store.write(KEYRING_FILE, &content)?;
+
+        if self.names.is_empty() {
+            if store.exists(INDEX_FILE)? {
+                store.remove(INDEX_FILE)?;
+            }
+        } else {
+            let plaintext = Zeroizing::new(serde_json::to_vec(&self.names)?);
+            let content =
+                container::seal_bytes(&mut self.scheme, &self.key, INDEX_FILE, &plaintext)?;
+            store.write(INDEX_FILE, &content)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Key> {
+        self.keys.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.keys.contains_key(name)
+    }
+
+    /* Adds the data key of a module. The keyring has to be written afterwards */
+    pub fn insert(&mut self, name: &str, key: &Key) {
+        self.keys.insert(name.to_owned(), key.clone());
+    }
+
+    /* Removes a module, which then has to be unlocked with its own password */
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.keys.remove(name).is_some()
+    }
+
+    /* Moves the data key of a module stored under a new name */
+    pub fn rename(&mut self, from: &str, to: &str) {
+        if let Some(key) = self.keys.remove(from) {
+            self.keys.insert(to.to_owned(), key);
+        }
+    }
+
+    /* Real name of the hidden module with the ID */
+    pub fn name(&self, id: &str) -> Option<&str> {
+        self.names.get(id).map(String::as_str)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.names
+            .iter()
+            .map(|(id, name)| (id.as_str(), name.as_str()))
+    }
+
+    /* Records the real name of a hidden module, or forgets it if name is None */
+    pub fn set_name(&mut self, id: &str, name: Option<&str>) {
+        match name {
+            Some(name) => self.names.insert(id.to_owned(), name.to_owned()),
+            None => self.names.remove(id),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::store::MemoryStore;
+
+    fn create(store: &MemoryStore) -> Keyring {
+        let scheme = EncryptionScheme::with_params(8, 1, 1).unwrap();
+        Keyring::create(store, "master", scheme).unwrap()
+    }
+
+    fn module_key(byte: u8) -> Key {
+        Key::new([byte; KEY_LEN])
+    }
+
+    #[test]
+    fn created_keyring_unlocks() {
+        let store = MemoryStore::new();
+        assert!(!Keyring::exists(&store).unwrap());
+        create(&store);
+        assert!(Keyring::exists(&store).unwrap());
+        let keyring = Keyring::unlock(&store, "master").unwrap();
+        assert_eq!(keyring.names().count(), 0);
+        assert!(!store.exists(INDEX_FILE).unwrap());
+    }
+
+    #[test]
+    fn wrong_master_password_is_rejected() {
+        let store = MemoryStore::new();
+        create(&store);
+        assert!(matches!(
+            Keyring::unlock(&store, "wrong"),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn keys_are_kept() {
+        let store = MemoryStore::new();
+        let mut keyring = create(&store);
+        keyring.insert("Private", &module_key(1));
+        keyring.insert("Work", &module_key(2));
+        keyring.write(&store).unwrap();
+
+        let mut keyring = Keyring::unlock(&store, "master").unwrap();
+        assert_eq!(keyring.get("Private"), Some(&module_key(1)));
+        assert_eq!(keyring.get("Work"), Some(&module_key(2)));
+        assert!(keyring.remove("Work"));
+        assert!(!keyring.remove("Work"));
+        keyring.rename("Private", "Secret");
+        keyring.write(&store).unwrap();
+
+        let keyring = Keyring::unlock(&store, "master").unwrap();
+        assert!(!keyring.contains("Private") && !keyring.contains("Work"));
+        assert_eq!(keyring.get("Secret"), Some(&module_key(1)));
+    }
+
+    #[test]
+    fn index_of_hidden_modules_is_kept() {
+        let store = MemoryStore::new();
+        let mut keyring = create(&store);
+        keyring.set_name("a1b2c3", Some("Bank"));
+        keyring.set_name("d4e5f6", Some("Mail"));
+        keyring.write(&store).unwrap();
+        assert!(store.exists(INDEX_FILE).unwrap());
+
+        let mut keyring = Keyring::unlock(&store, "master").unwrap();
+        assert_eq!(keyring.name("a1b2c3"), Some("Bank"));
+        assert_eq!(keyring.name("d4e5f6"), Some("Mail"));
+        keyring.set_name("a1b2c3", None);
+        keyring.set_name("d4e5f6", None);
+        keyring.write(&store).unwrap();
+        // An empty index is removed
+        assert!(!store.exists(INDEX_FILE).unwrap());
+        assert_eq!(
+            Keyring::unlock(&store, "master").unwrap().names().count(),
+            0
+        );
+    }
+
+    #[test]
+    fn index_is_bound_to_the_keyring() {
+        // An index sealed by another keyring doesn't open with this one
+        let store = MemoryStore::new();
+        let other = MemoryStore::new();
+        create(&store);
+        let mut keyring = create(&other);
+        keyring.set_name("a1b2c3", Some("Bank"));
+        keyring.write(&other).unwrap();
+        store
+            .write(INDEX_FILE, &other.read(INDEX_FILE).unwrap())
+            .unwrap();
+        assert!(Keyring::unlock(&store, "master").is_err());
+    }
+}
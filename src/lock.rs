@@ -0,0 +1,135 @@
+/*
+ * Advisory lock of a vault directory. Every instance writes the whole vault back when it quits,
+ * so two instances on the same vault would overwrite each other's changes. The instance that
+ * opens a vault for writing holds an OS file lock on pwmanager.lock in it and writes its PID and
+ * host into the file, so the instance in the way can be named.
+ *
+ * The lock file is never removed, since an instance could open it right before it is unlinked and
+ * lock a file no other instance sees. The OS releases the lock when its process ends, so a crash
+ * or a kill never leaves a stale lock behind.
+ * */
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/* Lock file in the vault directory */
+pub const LOCK_FILE: &str = "pwmanager.lock";
+
+/* Instance holding a lock */
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct Owner {
+    pid: u32,
+    host: String,
+}
+
+impl Owner {
+    fn current() -> Self {
+        Self {
+            pid: process::id(),
+            host: host_name(),
+        }
+    }
+}
+
+/* Lock on a vault directory, released when it is dropped */
+pub struct VaultLock {
+    file: File,
+}
+
+impl VaultLock {
+    /* Locks the vault in dir. Fails with Error::Locked if another running instance holds the
+     * lock */
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        // The file isn't truncated before it is locked, it names the instance holding the lock
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                // The owner is missing while the instance holding the lock still writes it
+                return Err(match ron::de::from_bytes::<Owner>(&fs::read(&path)?) {
+                    Ok(held) if held == Owner::current() => {
+                        Error::invalid("This vault is already open")
+                    }
+                    Ok(held) => Error::Locked {
+                        pid: held.pid,
+                        host: held.host,
+                    },
+                    Err(_) => Error::invalid("The vault is in use by another pwmanager"),
+                });
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        let mut lock = Self { file };
+        lock.file.set_len(0)?;
+        lock.file
+            .write_all(ron::ser::to_string(&Owner::current())?.as_bytes())?;
+        lock.file.sync_all()?;
+        Ok(lock)
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, which leaves room for the terminating NUL
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len() - 1) } != 0 {
+        return String::new();
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn host_name() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("pwmanager-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn lock_is_exclusive_until_dropped() {
+        let dir = test_dir("lock");
+        let lock = VaultLock::acquire(&dir).unwrap();
+        assert!(matches!(VaultLock::acquire(&dir), Err(Error::Invalid(_))));
+        drop(lock);
+        VaultLock::acquire(&dir).unwrap();
+    }
+
+    #[test]
+    fn leftover_lock_file_is_taken_over() {
+        // A file whose process died holds no OS lock
+        let dir = test_dir("stale_lock");
+        fs::write(dir.join(LOCK_FILE), "(pid:1,host:\"elsewhere\")").unwrap();
+        let _lock = VaultLock::acquire(&dir).unwrap();
+        let owner: Owner = ron::de::from_bytes(&fs::read(dir.join(LOCK_FILE)).unwrap()).unwrap();
+        assert!(owner == Owner::current());
+    }
+}
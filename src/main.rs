@@ -1,24 +1,34 @@
+pub mod backup;
+pub mod cipher;
+pub mod container;
+pub mod error;
+pub mod keyring;
+pub mod lock;
 pub mod password;
+pub mod payload;
 pub mod pbes;
+pub mod shutdown;
+pub mod store;
 pub mod ui;
+pub mod vaults;
 
-use crate::password::PasswordEntries;
-use crate::password::{ModuleList, HOME_ENV};
+use crate::error::Error;
+use crate::keyring::Keyring;
+use crate::password::ModuleList;
+use crate::store::FsStore;
 use crate::ui::{run_app, ModuleUI};
+use crate::vaults::{default_dir, Vaults};
 
 use std::collections::HashMap;
 use std::env;
-use std::io::{self, Read, Write};
-
-use std::fs::{self, File};
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::process;
 
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{self, EnableMouseCapture, Event, KeyCode},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{enable_raw_mode, EnterAlternateScreen},
 };
-use password::{decrypt_file, decrypt_from_file, encrypt_file, save_to_file};
 use pbes::EncryptionScheme;
 use tui::{
     backend::{Backend, CrosstermBackend},
@@ -55,46 +65,61 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     */
 
-    let mut base_path = PathBuf::from(env::var(HOME_ENV)?);
-    let mut file = env::var(HOME_ENV)?;
-    base_path.push(".pwmanager");
-    if (!(base_path.try_exists()?)) {
-        fs::create_dir(&base_path)?;
-    }
-    let enc_file = base_path.join("encryptions.ron");
-    let mut enc_file = File::open(&enc_file).ok();
-    let mut content = enc_file.as_ref().map(|_| Vec::new());
-    if let Some(enc) = enc_file.as_mut() {
-        enc.read_to_end(content.as_mut().unwrap())?;
-        drop(enc);
-    }
-    base_path.push("General.json");
-    let mut mod_list = ModuleList::get_module_list(content.as_ref())?;
-    if let Err(_) = File::open(&base_path) {
-        let et = PasswordEntries::new();
-        ModuleList::write_module("General", &et)?;
-        mod_list.add_module("General", et)?;
+    let mut vault = None;
+    let mut read_only = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--vault" => match args.next() {
+                Some(v) => vault = Some(v),
+                None => {
+                    println!("--vault needs a vault name or directory");
+                    return Ok(());
+                }
+            },
+            "--read-only" => read_only = true,
+            _ => {
+                println!("Invalid argument {}", arg);
+                return Ok(());
+            }
+        }
     }
+    let vaults = Vaults::load()?;
+    let (dir, vault) = match vault {
+        Some(vault) => (vaults.resolve(&vault)?, vault),
+        None => (default_dir()?, "default".to_owned()),
+    };
+    let store = match FsStore::new(dir, read_only) {
+        Ok(store) => store,
+        Err(e @ Error::Locked { .. }) => {
+            eprintln!("{}. Start with --read-only to view it without saving", e);
+            process::exit(1);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mod_list = ModuleList::open(Box::new(store))?;
+    let has_keyring = Keyring::exists(&*mod_list.store)?;
+    let exit = shutdown::register_signals()?;
+    shutdown::install_panic_hook();
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let app = ModuleUI::new(mod_list);
+    let mut app = ModuleUI::new(mod_list, vaults, vault);
+    // Modules left decrypted on disk are encrypted again first
+    if !app.prompt_recover() && has_keyring {
+        app.prompt_vault();
+    }
 
     // create app and run it
 
-    run_app(&mut terminal, app)?;
+    let result = run_app(&mut terminal, app, &exit);
 
-    // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+    // restore terminal, also if the app failed
+    shutdown::restore_terminal()?;
+    result?;
 
     Ok(())
 }
@@ -3,17 +3,20 @@ use rand::seq::index::sample;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use std::env;
-use std::fs;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use crate::pbes::EncryptionScheme;
-use ron::ser;
+use crate::backup;
+use crate::container;
+use crate::error::{Error, Result};
+use crate::payload;
+use crate::pbes::{EncryptionScheme, Key, Keyfile};
+use crate::store::VaultStore;
+use zeroize::{Zeroize, Zeroizing};
 
 //use der::Document;
 
@@ -49,9 +52,8 @@ pub fn add_password_64<'a>(
 }
 
 pub fn write_to_file(file: Option<&str>, entries: &PasswordEntries) -> io::Result<()> {
-    let f = File::create(file.unwrap_or("passwords.json"))?;
-    serde_json::to_writer(f, entries)?;
-    Ok(())
+    let content = Zeroizing::new(serde_json::to_vec(entries)?);
+    write_atomic(Path::new(file.unwrap_or("passwords.json")), &content)
 }
 
 pub fn read_from_file<'a, 'b>(file: Option<&'b str>) -> io::Result<PasswordEntries<'a>> {
@@ -60,101 +62,409 @@ pub fn read_from_file<'a, 'b>(file: Option<&'b str>) -> io::Result<PasswordEntri
     return Ok(pw_entry);
 }
 
+/* Length of the random IDs hidden modules are stored under, in hex digits */
+pub const HIDDEN_ID_LEN: usize = 32;
+
+/* Generates a random ID to store a module under instead of its name */
+pub fn new_hidden_id() -> String {
+    let bytes: [u8; HIDDEN_ID_LEN / 2] = thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/* Checks if a module file name is the ID of a hidden module */
+pub fn is_hidden_id(name: &str) -> bool {
+    name.len() == HIDDEN_ID_LEN && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/* Schemes of modules encrypted before the container format, see ModuleList::legacy */
+pub const ENCRYPTIONS_FILE: &str = "encryptions.ron";
+
+/* File of the module name in the vault */
+pub fn module_file(name: &str) -> String {
+    format!("{}.json", name)
+}
+
 /* Struct for list of modules */
 pub struct ModuleList<'a> {
+    /* Vault the modules are read from and written to */
+    pub store: Box<dyn VaultStore>,
+    /* Modules by the name of their file, which is the ID for hidden modules */
     pub modules: Vec<(Cow<'a, str>, Option<PasswordEntries<'a>>)>,
     pub encryptions: HashMap<Cow<'a, str>, EncryptionScheme<'a>>,
+    /* Encrypted modules without a header whose scheme still lives in encryptions.ron. They are
+     * migrated to the container format the next time they are saved */
+    pub legacy: HashSet<Cow<'a, str>>,
+    /* Legacy encrypted modules whose file holds plaintext, e.g. left by a session that died
+     * while they were decrypted on disk. Their passwords were exposed until re-encrypted */
+    pub exposed: HashSet<Cow<'a, str>>,
+    /* Modules whose file can't be parsed, with the reason. They are listed so the rest of the
+     * vault can still be used, but never opened or written */
+    pub unreadable: HashMap<Cow<'a, str>, String>,
+    /* Real names of hidden modules by their ID, known once the vault index is unlocked */
+    pub names: HashMap<Cow<'a, str>, String>,
 }
 
 impl<'b> ModuleList<'b> {
-    pub fn new() -> Self {
+    pub fn new(store: Box<dyn VaultStore>) -> Self {
         Self {
+            store,
             modules: Vec::new(),
             encryptions: HashMap::new(),
+            legacy: HashSet::new(),
+            exposed: HashSet::new(),
+            unreadable: HashMap::new(),
+            names: HashMap::new(),
         }
     }
 
-    pub fn add_module(
-        &mut self,
-        name: &str,
-        entries: PasswordEntries<'b>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut base_path = PathBuf::from(env::var(HOME_ENV)?);
-        let file_name = format!("{}.json", name);
-        let file_name = base_path.join(file_name);
-        return match File::open(&file_name) {
-            Ok(_) => Err(Box::new(io::Error::new(
-                io::ErrorKind::AlreadyExists,
-                "Module already exists.",
-            ))),
-            Err(e) => {
-                if e.kind() == io::ErrorKind::NotFound {
-                    self.modules
-                        .push((Cow::Owned(name.to_owned()), Some(entries)));
-                    Ok(())
-                } else {
-                    Err(Box::new(e))
-                }
-            }
-        };
+    /* Name of a module as shown to the user. Hidden modules get a placeholder while the index
+     * is locked */
+    pub fn display_name<'s>(&'s self, name: &'s str) -> Cow<'s, str> {
+        match self.names.get(name) {
+            Some(real_name) => Cow::Borrowed(real_name),
+            None if is_hidden_id(name) => Cow::Owned(format!("Hidden module {}", &name[..6])),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    pub fn add_module(&mut self, name: &str, entries: PasswordEntries<'b>) -> Result<()> {
+        if self.modules.iter().any(|m| self.display_name(&m.0) == name)
+            || self.store.exists(&module_file(name))?
+        {
+            return Err(Error::ModuleExists(name.to_owned()));
+        }
+        self.modules
+            .push((Cow::Owned(name.to_owned()), Some(entries)));
+        Ok(())
     }
 
     pub fn write_module(
+        store: &dyn VaultStore,
+        name: &str,
+        entries: &PasswordEntries,
+    ) -> Result<()> {
+        store.write(&module_file(name), &payload::encode(entries)?)?;
+        Ok(())
+    }
+
+    /* Encrypts the entries in memory with the data key and writes them to the module file. A
+     * module whose file already holds them is left alone, as every write adds a backup and
+     * unchanged saves would push the older versions out. A file that still holds plaintext,
+     * e.g. of a module encrypted in this session, is wiped */
+    pub fn write_encrypted_module(
+        store: &dyn VaultStore,
         name: &str,
+        ec: &mut EncryptionScheme,
+        key: &Key,
         entries: &PasswordEntries,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut base_path = PathBuf::from(env::var(HOME_ENV)?);
-        base_path.push(".pwmanager");
-
-        let file_name = format!("{}.json", name);
-        let file_name = base_path.join(file_name);
-        let f = File::create(&file_name)?;
-        serde_json::to_writer(f, entries)?;
+    ) -> Result<()> {
+        let file_name = module_file(name);
+        let old = match store.read(&file_name) {
+            Ok(old) => Some(Zeroizing::new(old)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(old) = old.as_ref() {
+            if Self::is_saved(old, name, ec, key, entries).unwrap_or(false) {
+                return Ok(());
+            }
+        }
+        let content = container::seal(ec, key, name, entries)?;
+        match old {
+            Some(old) if payload::is_plaintext(&old) => {
+                store.replace_plaintext(&file_name, &content)?
+            }
+            _ => {
+                backup::backup_module(store, name, &file_name)?;
+                store.write(&file_name, &content)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn encrypt_module<'a: 'b>(
+    /* Checks if the module file holds the entries sealed with the scheme in the current format */
+    fn is_saved(
+        content: &[u8],
+        name: &str,
+        ec: &EncryptionScheme,
+        key: &Key,
+        entries: &PasswordEntries,
+    ) -> Result<bool> {
+        let c = container::decode(content)?;
+        if c.header != container::encode_header(ec)? {
+            return Ok(false);
+        }
+        let plaintext = Zeroizing::new(ec.decrypt_with_key(
+            key,
+            c.ciphertext,
+            &container::aad(c.header, name),
+        )?);
+        Ok(payload::is_current(&plaintext) && payload::decode(&plaintext)? == *entries)
+    }
+
+    /* Decrypts an encrypted module in memory and returns its data key, so the module can be
+     * saved again without the password. The keyfile is needed for modules that require one.
+     * Modules of a legacy scheme are re-encrypted in the container format right away */
+    pub fn unlock_module(
         &mut self,
-        entry: &mut (Cow<'a, str>, Option<PasswordEntries<'a>>),
+        index: usize,
         password: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(_) = self.encryptions.get(&entry.0) {
-            return Ok(());
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Key> {
+        self.open_module(index, |ec| ec.unlock(password, keyfile), Some(password))
+    }
+
+    /* Decrypts an encrypted module in memory with its data key, e.g. from the vault keyring */
+    pub fn unlock_module_with_key(&mut self, index: usize, key: &Key) -> Result<()> {
+        self.open_module(index, |_| Ok(key.clone()), None)?;
+        Ok(())
+    }
+
+    fn open_module(
+        &mut self,
+        index: usize,
+        unlock: impl FnOnce(&EncryptionScheme) -> Result<Key>,
+        password: Option<&str>,
+    ) -> Result<Key> {
+        let m = self
+            .modules
+            .get_mut(index)
+            .ok_or_else(|| Error::invalid("No module selected"))?;
+        let f_name: &str = m.0.borrow();
+        let store = &*self.store;
+
+        let content = read_module_file(store, f_name)?;
+        if container::has_header(&content) {
+            let c = container::decode(&content)?;
+            let key = unlock(&c.scheme)?;
+            let et =
+                c.scheme
+                    .decrypt_entries(&key, c.ciphertext, &container::aad(c.header, f_name))?;
+            self.encryptions.insert(m.0.clone(), c.scheme);
+            m.1 = Some(et);
+            return Ok(key);
+        }
+
+        if self.exposed.contains(&m.0) {
+            return Err(Error::invalid(
+                "Module was left decrypted on disk, open it to encrypt it again",
+            ));
+        }
+        let ec = self
+            .encryptions
+            .get_mut(&m.0)
+            .ok_or_else(|| Error::invalid("Module is not encrypted"))?;
+        let mut key = unlock(ec)?;
+        // Legacy modules are bound to the absolute path of their file. Without a wrapped key
+        // nothing but the content tells if the password was right
+        let file = store
+            .legacy_path(&module_file(f_name))
+            .unwrap_or_else(|| module_file(f_name));
+        let et = ec
+            .decrypt_entries(&key, &content, file.as_bytes())
+            .map_err(|e| match e {
+                Error::Corrupt(_) => Error::WrongPassword,
+                e => e,
+            })?;
+        // Read-only vaults are viewed in the format they are in
+        if !store.read_only() {
+            // Legacy schemes encrypt with the password derived key, they get a wrapped data key
+            // before being written. They never use a keyfile
+            let password = password
+                .ok_or_else(|| Error::invalid("Module has to be unlocked with its password"))?;
+            key = ec.unlock_or_generate(password, None)?;
+            Self::write_encrypted_module(store, f_name, ec, &key, &et)?;
+            self.legacy.remove(&m.0);
+        }
+        m.1 = Some(et);
+        Ok(key)
+    }
+
+    /* Encrypts a module that was left decrypted on disk again and returns its data key. The
+     * password unlocks the data key of schemes that have one, legacy schemes without one get a
+     * new data key wrapped by the password. A read-only vault only loads the entries, the module
+     * stays exposed until the vault is opened for writing */
+    pub fn recover_module(&mut self, index: usize, password: &str) -> Result<Key> {
+        let m = self
+            .modules
+            .get_mut(index)
+            .ok_or_else(|| Error::invalid("No module selected"))?;
+        if !self.exposed.contains(&m.0) {
+            return Err(Error::invalid("Module is not left decrypted"));
+        }
+        let name: &str = m.0.borrow();
+        let et = payload::decode(&read_module_file(&*self.store, name)?)?;
+        let ec = self
+            .encryptions
+            .get_mut(&m.0)
+            .ok_or_else(|| Error::invalid("Module is not encrypted"))?;
+        if self.store.read_only() {
+            let key = ec.clone().unlock_or_generate(password, None)?;
+            m.1 = Some(et);
+            return Ok(key);
+        }
+        let key = ec.unlock_or_generate(password, None)?;
+        Self::write_encrypted_module(&*self.store, name, ec, &key, &et)?;
+        self.legacy.remove(&m.0);
+        self.exposed.remove(&m.0);
+        m.1 = Some(et);
+        Ok(key)
+    }
+
+    /* Changes the password of an encrypted module and returns its data key. The keyfile is
+     * needed for both passwords if the module requires one, and is added to modules without one.
+     * The data key is rewrapped with a new salt and the module is written again. The stored
+     * scheme is only replaced once the new file is in place, so a crash leaves the module
+     * readable with either the old or the new password */
+    pub fn change_password(
+        &mut self,
+        index: usize,
+        old_password: &str,
+        new_password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Key> {
+        let m = self
+            .modules
+            .get(index)
+            .ok_or_else(|| Error::invalid("No module selected"))?;
+        if !self.encryptions.contains_key(&m.0) {
+            return Err(Error::invalid("Module is not encrypted"));
+        }
+        // Unlocking the module verifies the old password and yields its data key
+        let unlocked_key = match m.1 {
+            Some(_) => None,
+            None => Some(self.unlock_module(index, old_password, keyfile)?),
+        };
+        let m = &self.modules[index];
+        let entries =
+            m.1.as_ref()
+                .ok_or_else(|| Error::invalid("Module is not unlocked"))?;
+        let ec = self
+            .encryptions
+            .get_mut(&m.0)
+            .ok_or_else(|| Error::invalid("Module is not encrypted"))?;
+
+        // Unlocking the data key verifies the old password. A scheme that was never written has
+        // no data key yet, so there is nothing the old password protects
+        let key = match unlocked_key {
+            Some(key) => key,
+            None if ec.wrapped_key.is_some() => ec.unlock(old_password, keyfile)?,
+            None => ec.unlock_or_generate(old_password, None)?,
+        };
+        let mut new_ec = ec.clone();
+        new_ec.rewrap(&key, new_password, keyfile)?;
+        Self::write_encrypted_module(&*self.store, &m.0, &mut new_ec, &key, entries)?;
+        *ec = new_ec;
+        self.legacy.remove(&m.0);
+        Ok(key)
+    }
+
+    /* Stores a loaded module under a new file name, e.g. the ID that hides it, and moves its
+     * backups along. Encrypted modules are sealed again with the data key, since the AAD binds
+     * them to their name. The old file is only removed once the new one is written */
+    pub fn rename_module(&mut self, index: usize, new_name: &str, key: Option<&Key>) -> Result<()> {
+        let store = &*self.store;
+        let m = self
+            .modules
+            .get(index)
+            .ok_or_else(|| Error::invalid("No module selected"))?;
+        let entries =
+            m.1.as_ref()
+                .ok_or_else(|| Error::invalid("Module is not unlocked"))?;
+        if store.exists(&module_file(new_name))? {
+            return Err(Error::ModuleExists(new_name.to_owned()));
+        }
+        match self.encryptions.get_mut(&m.0) {
+            Some(ec) => {
+                let key = key.ok_or_else(|| Error::invalid("Module is not unlocked"))?;
+                Self::write_encrypted_module(store, new_name, ec, key, entries)?;
+            }
+            None => Self::write_module(store, new_name, entries)?,
+        }
+        backup::rename_backups(store, &m.0, new_name)?;
+        let old_file = module_file(&m.0);
+        if store.exists(&old_file)? {
+            // Modules encrypted in this session still have plaintext on disk
+            if payload::is_plaintext(&Zeroizing::new(store.read(&old_file)?)) {
+                store.secure_remove(&old_file)?;
+            } else {
+                store.remove(&old_file)?;
+            }
         }
-        let mut base_path = PathBuf::from(env::var(HOME_ENV)?);
-        base_path.push(".pwmanager");
 
-        let file_name = format!("{}.json", &entry.0);
-        let file_name = base_path.join(file_name);
-        let ec = encrypt_file(password, &file_name.to_string_lossy())?;
-        self.encryptions.insert(entry.0.to_owned(), ec);
+        let old_name =
+            std::mem::replace(&mut self.modules[index].0, Cow::Owned(new_name.to_owned()));
+        if let Some(ec) = self.encryptions.remove(&old_name) {
+            self.encryptions.insert(Cow::Owned(new_name.to_owned()), ec);
+        }
+        self.legacy.remove(&old_name);
         Ok(())
     }
 
-    pub fn get_module_list(enc: Option<&'b Vec<u8>>) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut base_path = PathBuf::from(env::var(HOME_ENV)?);
-        base_path.push(".pwmanager");
-        let mut mod_list = Self::new();
-
-        let mut entry_iter = base_path.read_dir()?;
-        for entry in entry_iter.by_ref() {
-            if let Ok(p) = entry {
-                let path = p.path();
-                let mod_name = path
-                    .file_name()
-                    .and_then(|s| Path::new(s).file_stem())
-                    .unwrap()
-                    .to_string_lossy();
-                let extension = path.extension().unwrap();
-                if extension == "json" {
-                    mod_list
-                        .modules
-                        .push((Cow::from(mod_name.into_owned()), None));
+    /* Opens the vault in store. A new vault starts with the General module */
+    pub fn open(store: Box<dyn VaultStore>) -> Result<Self> {
+        let mut mod_list = Self::get_module_list(store)?;
+        // Checking for the General file instead would create it again after it was hidden
+        if mod_list.modules.is_empty() && !mod_list.store.read_only() {
+            mod_list.add_module("General", PasswordEntries::new())?;
+            Self::write_module(&*mod_list.store, "General", &PasswordEntries::new())?;
+        }
+        Ok(mod_list)
+    }
+
+    /* Lists the modules in the vault. Encrypted modules are detected from their header. Schemes
+     * from a legacy encryptions.ron are only used for modules without one */
+    pub fn get_module_list(store: Box<dyn VaultStore>) -> Result<Self> {
+        let mut mod_list = Self::new(store);
+        let enc = match mod_list.store.read(ENCRYPTIONS_FILE) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        for file in mod_list.store.list("")? {
+            // Skips the keyring and anything else that isn't a module file
+            if let Some(mod_name) = file.strip_suffix(".json") {
+                let content = mod_list.store.read(&file)?;
+                if container::has_header(&content) {
+                    match container::decode(&content) {
+                        Ok(c) => {
+                            mod_list
+                                .encryptions
+                                .insert(Cow::from(mod_name.to_owned()), c.scheme);
+                        }
+                        Err(e) => {
+                            mod_list
+                                .unreadable
+                                .insert(Cow::from(mod_name.to_owned()), e.to_string());
+                        }
+                    }
+                }
+                mod_list
+                    .modules
+                    .push((Cow::from(mod_name.to_owned()), None));
+            }
+        }
+        if let Some(content) = enc {
+            let legacy: HashMap<Cow<str>, EncryptionScheme> = ron::de::from_bytes(&content)
+                .map_err(|e| Error::corrupt(format!("{}: {}", ENCRYPTIONS_FILE, e)))?;
+            for (name, ec) in legacy {
+                // Modules with a header never use the legacy scheme, even if it can't be read
+                if mod_list.encryptions.contains_key(&name)
+                    || mod_list.unreadable.contains_key(&name)
+                {
+                    continue;
                 }
-                if let Some(content) = enc {
-                    mod_list.encryptions =
-                        ron::de::from_bytes(content).expect("FAILED GETTING LIST");
+                let name = Cow::from(name.into_owned());
+                // Legacy ciphertext never parses as a payload
+                match mod_list.store.read(&module_file(&name)) {
+                    Ok(content) if payload::decode(&content).is_ok() => {
+                        mod_list.exposed.insert(name.clone());
+                    }
+                    _ => {}
                 }
+                mod_list.legacy.insert(name.clone());
+                mod_list.encryptions.insert(name, ec);
             }
         }
         Ok(mod_list)
@@ -163,10 +473,139 @@ impl<'b> ModuleList<'b> {
     pub fn get_encryptions(&mut self) {}
 }
 
-/* Struct to hold a password */
-#[derive(Serialize, Deserialize, Debug)]
+/* Reads the file of the module name. A missing file is reported as missing module */
+pub fn read_module_file(store: &dyn VaultStore, name: &str) -> Result<Vec<u8>> {
+    store.read(&module_file(name)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::ModuleNotFound(name.to_owned()),
+        _ => e.into(),
+    })
+}
+
+/* Writes content to a temporary file next to path and renames it over path, so a crash leaves
+ * either the old or the new file. Every write to the vault goes through here. The temporary file
+ * is removed again if writing it fails */
+pub fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, |f| f.write_all(content))
+}
+
+/* Atomic write with the content written by write, so a failure partway through can be tested */
+fn write_atomic_with(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let written = create_replacement(&tmp_path, path).and_then(|mut f| {
+        write(&mut f)?;
+        f.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    sync_dir(path)
+}
+
+/* Creates the temporary file that replaces path. It gets the permissions of the file it replaces,
+ * so a vault restricted to the user stays that way. New files are only accessible by the user */
+fn create_replacement(tmp_path: &Path, path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(tmp_path)?;
+    match fs::metadata(path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // A temporary file left by a crash isn't created again, so its mode is reset
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(file)
+}
+
+/* Removes the file at path and makes the removal durable */
+pub fn remove_durable(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    sync_dir(path)
+}
+
+/* Overwrites the content of a file with zeros, then truncates it, flushing both to the disk.
+ * Flash storage and copy-on-write file systems may still keep the old blocks elsewhere, this
+ * only keeps the file system from handing the plaintext out again */
+fn wipe(file: &mut File) -> io::Result<()> {
+    let zeros = [0u8; 8192];
+    let mut left = file.metadata()?.len();
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()?;
+    file.set_len(0)?;
+    file.sync_all()
+}
+
+/* Wipes a file that held plaintext before removing it */
+pub fn secure_remove(path: &Path) -> io::Result<()> {
+    wipe(&mut OpenOptions::new().write(true).open(path)?)?;
+    remove_durable(path)
+}
+
+/* Replaces a file that held plaintext with content, e.g. its encryption, and wipes the
+ * plaintext. The old file is opened before the new one is renamed over it and wiped only after
+ * that, so a crash leaves either the plaintext or the new content in place */
+pub fn replace_plaintext(path: &Path, content: &[u8]) -> io::Result<()> {
+    let old = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    write_atomic(path, content)?;
+    match old {
+        Some(mut file) => wipe(&mut file),
+        None => Ok(()),
+    }
+}
+
+/* Flushes the directory containing path, so a rename or removal in it survives a crash. Windows
+ * can't open directories for this, a rename there is durable once it returns */
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/* Struct to hold a password. Owned passwords are wiped from memory when dropped */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Password<'a>(pub Cow<'a, str>);
 
+impl Drop for Password<'_> {
+    fn drop(&mut self) {
+        if let Cow::Owned(password) = &mut self.0 {
+            password.zeroize();
+        }
+    }
+}
+
 impl Password<'_> {
     /* Creates a password from the given password*/
     pub fn new_from(password: &str) -> Self {
@@ -208,83 +647,481 @@ impl Password<'_> {
     pub fn get(&self) -> &str {
         &*self.0
     }
-
-    pub fn encrypt_with_password<'a>(
-        &self,
-        file: &str,
-    ) -> Result<EncryptionScheme<'a>, Box<dyn std::error::Error>> {
-        encrypt_file(self.get(), file)
-    }
 }
 /* Creates an encryption scheme and saves to a file with name file */
-pub fn create_and_save_to_file(file: &str) -> Result<(), Box<dyn std::error::Error>> {
+pub fn create_and_save_to_file(file: &str) -> Result<()> {
     unimplemented!()
 }
 
-/* Encrypt file using the given password, salt and iv. Return the resulting encryption scheme */
-pub fn encrypt_file<'a>(
-    password: &str,
-    file: &str,
-) -> Result<EncryptionScheme<'a>, Box<dyn std::error::Error>> {
-    let ec = EncryptionScheme::default();
-    let mut f = File::open(file)?;
-    let mut content = Vec::new();
-    f.read_to_end(&mut content)?;
-    let encrypted_content = ec.encrypt(password, &content, file.as_bytes())?;
-
-    let mut f = File::create(file)?;
-    f.write_all(&encrypted_content)?;
-    Ok(ec)
-}
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
 
-pub fn save_to_file<'a>(
-    file: &str,
-    ec_scheme: &EncryptionScheme<'a>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let f = File::create(file)?;
-    ser::to_writer(f, ec_scheme)?;
-    Ok(())
-}
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
-pub fn decrypt_file<'a>(
-    password: &str,
-    file: &str,
-    ec_scheme: &EncryptionScheme<'a>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut encrypted_content: Vec<u8> = Vec::new();
-    let mut f = File::open(file)?;
-    f.read_to_end(&mut encrypted_content)?;
-    let decrypted_content = ec_scheme.decrypt(password, &encrypted_content, file.as_bytes())?;
-
-    let mut f = File::create(file)?;
-    f.write_all(&decrypted_content)?;
-    Ok(())
-}
+    use super::*;
+    use crate::lock::LOCK_FILE;
+    use crate::store::{FsStore, MemoryStore};
 
-/* Encrypt file with password, hash it and save scheme to a file*/
-pub fn password_encrypt_file(
-    password: &str,
-    file: &str,
-    der_file: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ec = encrypt_file(password, file)?;
-    save_to_file(file, &ec)?;
-    Ok(())
-}
+    /* Empty directory for a test, unique to the test and this process */
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pwmanager-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-/* Encrypt file with password using the scheme and hash from der_file */
+    fn tmp_file(path: &Path) -> PathBuf {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        PathBuf::from(tmp_path)
+    }
 
-/* Decrypt file with password and scheme from der file */
+    #[test]
+    fn write_atomic_replaces_content() {
+        let path = test_dir("write_atomic_replaces").join("module.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!tmp_file(&path).exists());
+    }
 
-pub fn decrypt_from_file(
-    password: &str,
-    file: &str,
-    scheme_file: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut f = File::open(scheme_file)?;
-    let mut content: Vec<u8> = Vec::new();
-    f.read_to_end(&mut content)?;
-    let ec: EncryptionScheme = ron::de::from_bytes(&content)?;
-    decrypt_file(password, file, &ec)?;
-    Ok(())
+    #[test]
+    fn failed_write_keeps_original() {
+        let path = test_dir("failed_write").join("module.json");
+        write_atomic(&path, b"original").unwrap();
+        let result = write_atomic_with(&path, |f| {
+            f.write_all(b"partial")?;
+            Err(io::Error::other("simulated failure"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        assert!(!tmp_file(&path).exists());
+    }
+
+    #[test]
+    fn failed_rename_keeps_original() {
+        // Renaming a file over a directory that isn't empty fails
+        let path = test_dir("failed_rename").join("module.json");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("original"), b"original").unwrap();
+        assert!(write_atomic(&path, b"new").is_err());
+        assert_eq!(fs::read(path.join("original")).unwrap(), b"original");
+        assert!(!tmp_file(&path).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("permissions");
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let path = dir.join("module.json");
+        write_atomic(&path, b"old").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(mode(&path), 0o640);
+
+        // A temporary file left behind with a wider mode doesn't widen a new file
+        let path = dir.join("new.json");
+        fs::write(tmp_file(&path), b"stale").unwrap();
+        fs::set_permissions(tmp_file(&path), fs::Permissions::from_mode(0o644)).unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    /* Scheme that is cheap to derive keys with, tests don't need the protection */
+    fn test_scheme<'a>() -> EncryptionScheme<'a> {
+        EncryptionScheme::with_params(8, 1, 1).unwrap()
+    }
+
+    fn test_entries<'a>() -> PasswordEntries<'a> {
+        let mut entries = PasswordEntries::new();
+        entries.insert(Cow::from("mail"), Password::new_from("secret"));
+        entries
+    }
+
+    fn index_of(mod_list: &ModuleList, name: &str) -> usize {
+        mod_list.modules.iter().position(|m| m.0 == name).unwrap()
+    }
+
+    #[test]
+    fn add_module_rejects_duplicates() {
+        let mut mod_list = ModuleList::open(Box::new(MemoryStore::new())).unwrap();
+        assert!(matches!(
+            mod_list.add_module("General", PasswordEntries::new()),
+            Err(Error::ModuleExists(_))
+        ));
+        mod_list.add_module("Work", PasswordEntries::new()).unwrap();
+        assert!(matches!(
+            mod_list.add_module("Work", PasswordEntries::new()),
+            Err(Error::ModuleExists(_))
+        ));
+        // A file in the vault counts even if the module isn't listed
+        mod_list.store.write("Other.json", b"{}").unwrap();
+        assert!(matches!(
+            mod_list.add_module("Other", PasswordEntries::new()),
+            Err(Error::ModuleExists(_))
+        ));
+    }
+
+    #[test]
+    fn encrypted_module_unlocks_after_reopening() {
+        let store = MemoryStore::new();
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        ModuleList::write_module(&store, "General", &PasswordEntries::new()).unwrap();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &test_entries())
+            .unwrap();
+
+        let mut mod_list = ModuleList::open(Box::new(store)).unwrap();
+        assert_eq!(mod_list.modules.len(), 2);
+        assert!(mod_list.encryptions.contains_key("Private"));
+        assert!(!mod_list.encryptions.contains_key("General"));
+        let i = index_of(&mod_list, "Private");
+        assert!(mod_list.modules[i].1.is_none());
+        assert!(matches!(
+            mod_list.unlock_module(i, "wrong", None),
+            Err(Error::WrongPassword)
+        ));
+        let unlocked = mod_list.unlock_module(i, "password", None).unwrap();
+        assert_eq!(*unlocked, *key);
+        assert_eq!(mod_list.modules[i].1, Some(test_entries()));
+    }
+
+    #[test]
+    fn tampered_module_is_corrupt_not_a_wrong_password() {
+        let store = MemoryStore::new();
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &test_entries())
+            .unwrap();
+        let mut content = store.read("Private.json").unwrap();
+        *content.last_mut().unwrap() ^= 1;
+        store.write("Private.json", &content).unwrap();
+
+        let mut mod_list = ModuleList::open(Box::new(store)).unwrap();
+        let i = index_of(&mod_list, "Private");
+        assert!(matches!(
+            mod_list.unlock_module(i, "password", None),
+            Err(Error::Corrupt(_))
+        ));
+        assert!(matches!(
+            mod_list.unlock_module(i, "wrong", None),
+            Err(Error::WrongPassword)
+        ));
+        assert!(mod_list.modules[i].1.is_none());
+    }
+
+    #[test]
+    fn rename_module_moves_file_and_backups() {
+        let store = MemoryStore::new();
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        // The second write backs up the first
+        ModuleList::write_encrypted_module(
+            &store,
+            "Private",
+            &mut ec,
+            &key,
+            &PasswordEntries::new(),
+        )
+        .unwrap();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &test_entries())
+            .unwrap();
+        ModuleList::write_module(&store, "Notes", &test_entries()).unwrap();
+
+        let mut mod_list = ModuleList::open(Box::new(store)).unwrap();
+        let i = index_of(&mod_list, "Private");
+        mod_list.unlock_module(i, "password", None).unwrap();
+        mod_list.rename_module(i, "Secret", Some(&key)).unwrap();
+        assert_eq!(mod_list.modules[i].0, "Secret");
+        assert!(mod_list.encryptions.contains_key("Secret"));
+        assert!(!mod_list.encryptions.contains_key("Private"));
+        let store = &*mod_list.store;
+        assert!(!store.exists("Private.json").unwrap());
+        assert!(!store.exists("backups/Private").unwrap());
+        assert_eq!(store.list("backups/Secret").unwrap().len(), 1);
+
+        // Renaming to a module that exists fails and leaves both alone
+        let j = index_of(&mod_list, "Notes");
+        mod_list.modules[j].1 = Some(test_entries());
+        assert!(matches!(
+            mod_list.rename_module(j, "Secret", None),
+            Err(Error::ModuleExists(_))
+        ));
+        mod_list.rename_module(j, "Journal", None).unwrap();
+        assert!(!mod_list.store.exists("Notes.json").unwrap());
+
+        // The renamed module is bound to its new name
+        let mut mod_list = ModuleList::open(mod_list.store).unwrap();
+        let i = index_of(&mod_list, "Secret");
+        mod_list.unlock_module(i, "password", None).unwrap();
+        assert_eq!(mod_list.modules[i].1, Some(test_entries()));
+        let journal = read_module_file(&*mod_list.store, "Journal").unwrap();
+        assert_eq!(payload::decode(&journal).unwrap(), test_entries());
+    }
+
+    #[test]
+    fn read_only_vault_is_not_written() {
+        let dir = test_dir("read_only");
+        let mut legacy = HashMap::new();
+        legacy.insert("Exposed", test_scheme());
+        fs::write(
+            dir.join(ENCRYPTIONS_FILE),
+            ron::ser::to_string(&legacy).unwrap(),
+        )
+        .unwrap();
+        let plaintext = payload::encode(&test_entries()).unwrap();
+        fs::write(dir.join("Exposed.json"), &plaintext).unwrap();
+
+        let store = FsStore::new(dir.clone(), true).unwrap();
+        let mut mod_list = ModuleList::open(Box::new(store)).unwrap();
+        assert!(mod_list.modules.len() == 1 && mod_list.exposed.contains("Exposed"));
+        mod_list.recover_module(0, "password").unwrap();
+        assert_eq!(mod_list.modules[0].1, Some(test_entries()));
+        assert!(mod_list.exposed.contains("Exposed"));
+        assert_eq!(fs::read(dir.join("Exposed.json")).unwrap(), *plaintext);
+        assert!(!dir.join(LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn corrupt_module_is_listed_as_unreadable() {
+        let store = MemoryStore::new();
+        ModuleList::write_module(&store, "General", &test_entries()).unwrap();
+        let mut broken = container::MAGIC.to_vec();
+        broken.extend_from_slice(&[2, 0, 0xff, 0, 0, 0, b'(']);
+        store.write("Broken.json", &broken).unwrap();
+
+        let mod_list = ModuleList::open(Box::new(store)).unwrap();
+        assert_eq!(mod_list.modules.len(), 2);
+        assert!(mod_list.unreadable.contains_key("Broken"));
+        assert!(!mod_list.unreadable.contains_key("General"));
+        assert!(mod_list.encryptions.is_empty());
+    }
+
+    /* Vault with a legacy module whose scheme is in encryptions.ron, which is replaced by the
+     * given content */
+    fn legacy_vault(encryptions: Option<&[u8]>) -> (MemoryStore, Vec<u8>) {
+        let store = MemoryStore::new();
+        let ec = test_scheme();
+        let key = ec.unlock("password", None).unwrap();
+        let plaintext = payload::encode(&test_entries()).unwrap();
+        let ciphertext = ec
+            .encrypt_with_key(&key, &plaintext, module_file("Old").as_bytes())
+            .unwrap();
+        store.write(&module_file("Old"), &ciphertext).unwrap();
+        let mut legacy = HashMap::new();
+        legacy.insert("Old", ec);
+        let content = ron::ser::to_string(&legacy).unwrap().into_bytes();
+        store
+            .write(ENCRYPTIONS_FILE, encryptions.unwrap_or(&content))
+            .unwrap();
+        (store, content)
+    }
+
+    /* Opens the legacy module of the vault */
+    fn unlock_legacy(store: MemoryStore) -> Result<()> {
+        let mut mod_list = ModuleList::get_module_list(Box::new(store))?;
+        let i = index_of(&mod_list, "Old");
+        mod_list.unlock_module(i, "password", None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_encryptions_file_is_rejected() {
+        let (store, content) = legacy_vault(None);
+        unlock_legacy(store).unwrap();
+        for len in 0..content.len() {
+            let (store, _) = legacy_vault(Some(&content[..len]));
+            assert!(unlock_legacy(store).is_err(), "truncated to {}", len);
+        }
+        // Flips that still parse change the module name or the scheme, except for the few the
+        // scheme parser ignores
+        for i in 0..content.len() {
+            for bit in 0..8 {
+                let mut flipped = content.clone();
+                flipped[i] ^= 1 << bit;
+                let same = ron::de::from_bytes::<HashMap<String, EncryptionScheme>>(&flipped)
+                    .is_ok_and(|map| {
+                        ron::ser::to_string(&map).is_ok_and(|s| s.as_bytes() == content)
+                    });
+                let (store, _) = legacy_vault(Some(&flipped));
+                assert!(
+                    same || unlock_legacy(store).is_err(),
+                    "flipped bit {} of byte {}",
+                    bit,
+                    i
+                );
+            }
+        }
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            let mut random = vec![0u8; rng.gen_range(0..256)];
+            rng.fill(random.as_mut_slice());
+            let (store, _) = legacy_vault(Some(&random));
+            assert!(unlock_legacy(store).is_err());
+        }
+    }
+
+    /* Store that records which files were written and wiped. It is shared, so it can still be
+     * inspected once a ModuleList owns it */
+    #[derive(Default)]
+    struct LogStore {
+        files: MemoryStore,
+        written: RefCell<Vec<String>>,
+        wiped: RefCell<Vec<String>>,
+        /* Makes every write fail, like a full disk */
+        fail_writes: Cell<bool>,
+    }
+
+    impl VaultStore for Rc<LogStore> {
+        fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+            self.files.read(path)
+        }
+        fn write(&self, path: &str, content: &[u8]) -> io::Result<()> {
+            if self.fail_writes.get() {
+                return Err(io::Error::other("simulated failure"));
+            }
+            self.written.borrow_mut().push(path.to_owned());
+            self.files.write(path, content)
+        }
+        fn remove(&self, path: &str) -> io::Result<()> {
+            self.files.remove(path)
+        }
+        fn replace_plaintext(&self, path: &str, content: &[u8]) -> io::Result<()> {
+            self.wiped.borrow_mut().push(path.to_owned());
+            self.files.write(path, content)
+        }
+        fn secure_remove(&self, path: &str) -> io::Result<()> {
+            self.wiped.borrow_mut().push(path.to_owned());
+            self.files.remove(path)
+        }
+        fn exists(&self, path: &str) -> io::Result<bool> {
+            self.files.exists(path)
+        }
+        fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+            self.files.list(dir)
+        }
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.files.rename(from, to)
+        }
+    }
+
+    #[test]
+    fn encrypting_plaintext_module_wipes_it() {
+        let store = Rc::new(LogStore::default());
+        ModuleList::write_module(&store, "Private", &test_entries()).unwrap();
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &test_entries())
+            .unwrap();
+        assert_eq!(*store.wiped.borrow(), ["Private.json"]);
+        assert!(container::has_header(&store.read("Private.json").unwrap()));
+        assert!(!store.exists("backups/Private").unwrap());
+
+        // Encrypted files are backed up instead
+        let mut entries = test_entries();
+        entries.insert(Cow::from("bank"), Password::new_from("other"));
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &entries).unwrap();
+        assert_eq!(store.wiped.borrow().len(), 1);
+        assert_eq!(store.list("backups/Private").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn change_password_rewraps_the_data_key() {
+        let store = Rc::new(LogStore::default());
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("old", None).unwrap();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &test_entries())
+            .unwrap();
+        let mut mod_list = ModuleList::open(Box::new(store.clone())).unwrap();
+        let i = index_of(&mod_list, "Private");
+
+        assert!(matches!(
+            mod_list.change_password(i, "wrong", "new", None),
+            Err(Error::WrongPassword)
+        ));
+        store.written.borrow_mut().clear();
+        let changed = mod_list.change_password(i, "old", "new", None).unwrap();
+        // The data key stays, so keys in the keyring stay valid
+        assert_eq!(changed, key);
+        // The module file is replaced by a single atomic write, next to its backup
+        let written = store.written.borrow().clone();
+        assert_eq!(written.iter().filter(|p| *p == "Private.json").count(), 1);
+        assert!(written
+            .iter()
+            .all(|p| p == "Private.json" || p.starts_with("backups/")));
+
+        let mut mod_list = ModuleList::open(Box::new(store.clone())).unwrap();
+        assert!(matches!(
+            mod_list.unlock_module(i, "old", None),
+            Err(Error::WrongPassword)
+        ));
+        assert_eq!(mod_list.unlock_module(i, "new", None).unwrap(), key);
+        assert_eq!(mod_list.modules[i].1, Some(test_entries()));
+        let mut mod_list = ModuleList::open(Box::new(store.clone())).unwrap();
+        mod_list.unlock_module_with_key(i, &key).unwrap();
+        assert_eq!(mod_list.modules[i].1, Some(test_entries()));
+    }
+
+    #[test]
+    fn failed_password_change_keeps_the_old_password() {
+        let store = Rc::new(LogStore::default());
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("old", None).unwrap();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &test_entries())
+            .unwrap();
+        let mut mod_list = ModuleList::open(Box::new(store.clone())).unwrap();
+        let i = index_of(&mod_list, "Private");
+        mod_list.unlock_module(i, "old", None).unwrap();
+
+        store.fail_writes.set(true);
+        assert!(mod_list.change_password(i, "old", "new", None).is_err());
+        store.fail_writes.set(false);
+        // Neither the file nor the scheme in memory took the new password
+        assert!(mod_list.encryptions["Private"].unlock("new", None).is_err());
+        mod_list.encryptions["Private"].unlock("old", None).unwrap();
+        let mut mod_list = ModuleList::open(Box::new(store.clone())).unwrap();
+        mod_list.unlock_module(i, "old", None).unwrap();
+    }
+
+    #[test]
+    fn saving_derives_no_key() {
+        let store = MemoryStore::new();
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        // Argon2 refuses salts this short, so any key derivation would fail the save
+        ec.salt = password_hash::SaltString::new("c2FsdA").unwrap();
+        let runs = crate::pbes::KDF_RUNS.with(|runs| runs.get());
+        let mut entries = test_entries();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &entries).unwrap();
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &entries).unwrap();
+        entries.insert(Cow::from("bank"), Password::new_from("other"));
+        ModuleList::write_encrypted_module(&store, "Private", &mut ec, &key, &entries).unwrap();
+        assert_eq!(crate::pbes::KDF_RUNS.with(|runs| runs.get()), runs);
+    }
+
+    #[test]
+    fn unchanged_save_adds_no_backup() {
+        let store = MemoryStore::new();
+        let mut ec = test_scheme();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        let mut entries = test_entries();
+        let backups = || store.list("backups/test").map_or(0, |b| b.len());
+
+        ModuleList::write_encrypted_module(&store, "test", &mut ec, &key, &entries).unwrap();
+        ModuleList::write_encrypted_module(&store, "test", &mut ec, &key, &entries).unwrap();
+        assert_eq!(backups(), 0);
+
+        entries.insert(Cow::from("bank"), Password::new_from("other"));
+        ModuleList::write_encrypted_module(&store, "test", &mut ec, &key, &entries).unwrap();
+        assert_eq!(backups(), 1);
+        ModuleList::write_encrypted_module(&store, "test", &mut ec, &key, &entries).unwrap();
+        assert_eq!(backups(), 1);
+    }
 }
@@ -0,0 +1,170 @@
+/*
+ * Payload of a module, i.e. the content of a plaintext module file or the plaintext of an
+ * encrypted one. It is a JSON envelope holding the version of the payload schema and the
+ * entries:
+ * {"version": 1, "entries": {"<name>": "<password>", ...}}
+ *
+ * Version 0 is the bare map of entries written before the envelope. Older payloads are upgraded
+ * by the migrations when they are loaded and written in the current version the next time the
+ * module is saved. Payloads of a newer version are refused, since loading and saving them again
+ * would drop whatever this version doesn't know.
+ * */
+
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+use crate::password::PasswordEntries;
+
+/* Version of the payload schema written by this build */
+pub const PAYLOAD_VERSION: u32 = 1;
+
+/* Upgrades the entries of the version at their index to the next version. Adding a version
+ * means appending its migration here and raising PAYLOAD_VERSION */
+const MIGRATIONS: [fn(Value) -> Result<Value>; PAYLOAD_VERSION as usize] = [from_bare_map];
+
+#[derive(Serialize)]
+struct Envelope<'e, 'a> {
+    version: u32,
+    entries: &'e PasswordEntries<'a>,
+}
+
+/* Envelope of the current version. Its version was already checked */
+#[derive(Deserialize)]
+struct StoredEnvelope<'a> {
+    entries: PasswordEntries<'a>,
+}
+
+/* Reads only the version of a payload */
+#[derive(Deserialize)]
+struct Header {
+    version: Option<u32>,
+}
+
+/* Serializes the entries in the current version */
+pub fn encode(entries: &PasswordEntries) -> Result<Zeroizing<Vec<u8>>> {
+    let envelope = Envelope {
+        version: PAYLOAD_VERSION,
+        entries,
+    };
+    Ok(Zeroizing::new(serde_json::to_vec(&envelope)?))
+}
+
+/* Parses a payload of any version up to the current one */
+pub fn decode<'a>(payload: &[u8]) -> Result<PasswordEntries<'a>> {
+    let version = payload_version(payload);
+    if version > PAYLOAD_VERSION {
+        return Err(Error::UnsupportedPayloadVersion(version));
+    }
+    if version == PAYLOAD_VERSION {
+        let envelope: StoredEnvelope = serde_json::from_slice(payload)?;
+        return Ok(envelope.entries);
+    }
+
+    // Migrating works on untyped JSON, whose strings are not wiped from memory. That only
+    // happens once per module, as it is saved in the current version afterwards
+    let mut entries: Value = serde_json::from_slice(payload)?;
+    if version > 0 {
+        entries = entries
+            .get_mut("entries")
+            .map(Value::take)
+            .ok_or_else(|| Error::corrupt("Module payload has no entries"))?;
+    }
+    for migrate in &MIGRATIONS[version as usize..] {
+        entries = migrate(entries)?;
+    }
+    Ok(serde_json::from_value(entries)?)
+}
+
+/* Checks if the content of a module file is plaintext of any version. Encrypted files never
+ * parse as JSON */
+pub fn is_plaintext(content: &[u8]) -> bool {
+    serde_json::from_slice::<IgnoredAny>(content).is_ok()
+}
+
+/* Checks if a payload is in the current version, so saving it again would change nothing */
+pub fn is_current(payload: &[u8]) -> bool {
+    payload_version(payload) == PAYLOAD_VERSION
+}
+
+/* Version of a payload. A bare map of entries has none, its values are all strings, so an entry
+ * named version can't be mistaken for one */
+fn payload_version(payload: &[u8]) -> u32 {
+    serde_json::from_slice::<Header>(payload)
+        .ok()
+        .and_then(|header| header.version)
+        .unwrap_or(0)
+}
+
+/* Version 0 to 1: the entries are unchanged, they only moved into the envelope */
+fn from_bare_map(entries: Value) -> Result<Value> {
+    match entries {
+        Value::Object(_) => Ok(entries),
+        _ => Err(Error::corrupt("Module payload is not a map of entries")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::password::Password;
+
+    #[test]
+    fn bare_map_is_upgraded() {
+        let entries = decode(br#"{"mail":"secret","bank":"other"}"#).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["mail"].get(), "secret");
+
+        // It is saved back in the current version
+        let payload = encode(&entries).unwrap();
+        assert!(is_current(&payload));
+        let saved: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(saved["version"], PAYLOAD_VERSION);
+        assert_eq!(saved["entries"]["bank"], "other");
+        assert_eq!(decode(&payload).unwrap(), entries);
+    }
+
+    #[test]
+    fn entry_named_version_is_not_a_version() {
+        let payload = br#"{"version":"hunter2","mail":"secret"}"#;
+        assert!(!is_current(payload));
+        let entries = decode(payload).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["version"].get(), "hunter2");
+
+        // Also in the current version, where it is an entry like any other
+        let mut entries = PasswordEntries::new();
+        entries.insert(Cow::from("version"), Password::new_from("7"));
+        assert_eq!(decode(&encode(&entries).unwrap()).unwrap(), entries);
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        let payload = format!(r#"{{"version":{},"entries":{{}}}}"#, PAYLOAD_VERSION + 1);
+        assert!(matches!(
+            decode(payload.as_bytes()),
+            Err(Error::UnsupportedPayloadVersion(v)) if v == PAYLOAD_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn malformed_payload_is_rejected() {
+        for payload in [
+            &b"[]"[..],
+            br#"{"mail":1}"#,
+            br#"{"version":1}"#,
+            br#"{"version":1,"entries":[]}"#,
+            b"{",
+        ] {
+            assert!(
+                decode(payload).is_err(),
+                "{}",
+                String::from_utf8_lossy(payload)
+            );
+        }
+    }
+}
@@ -6,112 +6,388 @@
  * */
 
 use serde::{
-    de::{self, Deserialize, Deserializer, Error, MapAccess, Visitor},
+    de::{self, Deserialize, Deserializer, MapAccess, Visitor},
     ser::{self, Serialize, SerializeStruct, Serializer},
 };
 use std::{
     fmt::{self},
-    io::{self, Read, Write},
+    marker::PhantomData,
+    thread,
+    time::{Duration, Instant},
 };
 
 use chacha20poly1305::{
-    aead::{generic_array::GenericArray, Aead, AeadCore, OsRng, Payload},
-    ChaCha20Poly1305, KeyInit, Nonce,
+    aead::{rand_core::RngCore, OsRng},
+    Nonce,
 };
-use std::fs::File;
+use std::fs;
+use zeroize::Zeroizing;
 
+use crate::cipher::CipherSuite;
+use crate::error::{Error, Result};
+use crate::password::PasswordEntries;
+use crate::payload;
 use argon2::{Algorithm, Argon2, Params, Version};
+use blake2::{
+    digest::consts::{U32, U8},
+    Blake2b, Digest,
+};
 use password_hash::{
-    self, PasswordHash, PasswordHashString, PasswordHasher, PasswordVerifier, SaltString,
+    self, ParamsString, PasswordHash, PasswordHashString, PasswordHasher, PasswordVerifier,
+    SaltString,
 };
 
+/* Length of the legacy ChaCha20Poly1305 nonce */
+pub const NONCE_LEN: usize = 12;
+/* Length of the data key and of the key derived from the password */
+pub const KEY_LEN: usize = 32;
+/* A data key or a key derived from a password. Wiped from memory when dropped */
+pub type Key = Zeroizing<[u8; KEY_LEN]>;
+/* Length of the authentication tag of every cipher suite */
+const TAG_LEN: usize = 16;
+/* AAD used when wrapping a data key */
+const WRAP_AAD: &[u8] = b"pwmanager data key";
+/* Length of the value checking that the right keyfile is used */
+const KEYFILE_CHECK_LEN: usize = 8;
+/* Length of the value checking that the key derived from the password is right */
+const KEY_CHECK_LEN: usize = 8;
+/* Unlock time aimed for when the Argon2 parameters are calibrated */
+pub const DEFAULT_UNLOCK_TARGET: Duration = Duration::from_millis(500);
+/* Upper bound for the calibrated memory cost in KiB (1 GiB) */
+const CALIBRATION_MAX_M_COST: u32 = 1024 * 1024;
+/* Upper bounds for the iterations and the degree of parallelism. Together with the memory cost
+ * bounded by CALIBRATION_MAX_M_COST they keep a corrupt or hostile header from exhausting the
+ * memory or hanging the program when a key is derived */
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 16;
+/* Lower bound for the calibrated memory cost in KiB (1 MiB) on machines too slow for the
+ * defaults */
+const CALIBRATION_MIN_M_COST: u32 = 1024;
+
+#[cfg(test)]
+thread_local! {
+    /* Key derivations run by this thread, to test which operations cost one */
+    pub static KDF_RUNS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[derive(Clone)]
 pub struct EncryptionScheme<'a> {
     pub kdf: Argon2<'a>,
+    /* Variant and version kdf was created with. Argon2 doesn't expose them, they are kept to
+     * write the PHC string without deriving a key */
+    pub algorithm: Algorithm,
+    pub version: Version,
     pub salt: SaltString,
-    pub nonce: Nonce,
+    /* Nonce shared by every encryption of a legacy scheme. None once the module has been
+     * re-encrypted, since each ciphertext then carries its own nonce. */
+    pub nonce: Option<Nonce>,
+    /* Random data key encrypted with the key derived from the password. None for legacy schemes
+     * which encrypt the content with the derived key directly. */
+    pub wrapped_key: Option<Vec<u8>>,
+    /* AEAD used for the content and for wrapping the data key */
+    pub suite: CipherSuite,
+    /* Check value of the keyfile that has to be used together with the password. None if the
+     * module is unlocked with the password alone */
+    pub keyfile: Option<Vec<u8>>,
+    /* Check value of the key derived from the password, so a wrong password is told apart from
+     * a corrupt file without decrypting anything. Present exactly when a data key is wrapped */
+    pub check: Option<Vec<u8>>,
+}
+
+/* Digest of a keyfile. It is mixed into the key derivation as a second factor besides the
+ * password */
+pub struct Keyfile(Zeroizing<[u8; KEY_LEN]>);
+
+impl Keyfile {
+    /* Reads the keyfile at path. Any file can be used as keyfile */
+    pub fn read(path: &str) -> Result<Self> {
+        let content = Zeroizing::new(
+            fs::read(path)
+                .map_err(|e| Error::invalid(format!("Cannot read keyfile {}: {}", path, e)))?,
+        );
+        if content.is_empty() {
+            return Err(Error::invalid(format!("Keyfile {} is empty", path)));
+        }
+        let mut digest = Zeroizing::new([0u8; KEY_LEN]);
+        digest.copy_from_slice(&Blake2b::<U32>::digest(content.as_slice()));
+        Ok(Self(digest))
+    }
+
+    /* Short value stored in the scheme to tell a wrong keyfile apart from a wrong password. It
+     * depends on the salt, so it changes with every new salt */
+    fn check(&self, salt: &SaltString) -> Vec<u8> {
+        Blake2b::<U8>::new()
+            .chain_update(b"pwmanager keyfile")
+            .chain_update(salt.as_bytes())
+            .chain_update(self.0.as_slice())
+            .finalize()
+            .to_vec()
+    }
+}
+
+/* Checks that deriving a key with the parameters stays within the bounds of this program */
+fn check_params(params: &Params) -> std::result::Result<(), String> {
+    if params.m_cost() > CALIBRATION_MAX_M_COST {
+        return Err(format!(
+            "Memory cost above {} KiB is not supported",
+            CALIBRATION_MAX_M_COST
+        ));
+    }
+    if params.t_cost() > MAX_T_COST {
+        return Err(format!(
+            "More than {} iterations are not supported",
+            MAX_T_COST
+        ));
+    }
+    if params.p_cost() > MAX_P_COST {
+        return Err(format!("Parallelism above {} is not supported", MAX_P_COST));
+    }
+    Ok(())
+}
+
+/* Check value of a key derived from a password. Leaks nothing about the key, as long as the
+ * hash is one-way */
+fn key_check(kek: &Key) -> Vec<u8> {
+    Blake2b::<U8>::new()
+        .chain_update(b"pwmanager key check")
+        .chain_update(kek.as_slice())
+        .finalize()
+        .to_vec()
 }
 
 impl<'a> EncryptionScheme<'a> {
-    pub fn encrypt(
-        &self,
-        password: impl AsRef<[u8]>,
-        plaintext: &[u8],
-        aad: &[u8],
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let password_hash = self
-            .kdf
-            .hash_password(password.as_ref(), &self.salt)
-            .map_err(|E| io::Error::new(io::ErrorKind::Other, E.to_string()))?;
-
-        let hash = password_hash.hash.ok_or("No hash found")?;
-        let cipher = ChaCha20Poly1305::new_from_slice(hash.as_bytes())
-            .map_err(|E| io::Error::new(io::ErrorKind::Other, E.to_string()))?;
-
-        let payload = Payload {
-            msg: plaintext,
-            aad,
-        };
-        let encrypted_content = cipher
-            .encrypt(&self.nonce, payload)
-            .map_err(|E| io::Error::new(io::ErrorKind::Other, E.to_string()))?;
-        return Ok(encrypted_content);
+    /* Creates a scheme using Argon2id with the memory cost in KiB, the number of iterations and
+     * the degree of parallelism */
+    pub fn with_params(m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Self> {
+        let params =
+            Params::new(m_cost, t_cost, p_cost, None).map_err(|e| Error::invalid(e.to_string()))?;
+        check_params(&params).map_err(Error::invalid)?;
+        Ok(Self {
+            kdf: Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+            algorithm: Algorithm::Argon2id,
+            version: Version::V0x13,
+            ..Self::default()
+        })
     }
 
-    pub fn decrypt(
-        &self,
-        password: impl AsRef<[u8]>,
-        ciphertext: &[u8],
-        aad: &[u8],
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let password_hash = self
-            .kdf
-            .hash_password(password.as_ref(), &self.salt)
-            .map_err(|E| io::Error::new(io::ErrorKind::Other, E.to_string()))?;
-
-        let hash = password_hash.hash.ok_or("No hash found")?;
-        let cipher = ChaCha20Poly1305::new_from_slice(hash.as_bytes())
-            .map_err(|E| io::Error::new(io::ErrorKind::Other, E.to_string()))?;
-
-        let payload = Payload {
-            msg: ciphertext,
-            aad,
+    /* Creates a scheme from the options typed in when encrypting a module. Empty options use the
+     * defaults. Otherwise a comma separated list of m=<KiB>, t=<iterations>, p=<parallelism> and
+     * cipher=<suite> is expected, where "auto" in place of the Argon2 parameters calibrates them
+     * for this machine. Options that are left out keep their default value */
+    pub fn from_options(options: &str) -> Result<Self> {
+        let mut m_cost = Params::DEFAULT_M_COST;
+        let mut t_cost = Params::DEFAULT_T_COST;
+        let mut p_cost = Params::DEFAULT_P_COST;
+        let mut suite = CipherSuite::default();
+        let mut calibrate = false;
+        for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            if option == "auto" {
+                calibrate = true;
+                continue;
+            }
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| Error::invalid(format!("Invalid option {}", option)))?;
+            let (key, value) = (key.trim(), value.trim());
+            let parse = |value: &str| -> Result<u32> {
+                value
+                    .parse()
+                    .map_err(|_| Error::invalid(format!("Invalid value for {}", key)))
+            };
+            match key {
+                "m" => m_cost = parse(value)?,
+                "t" => t_cost = parse(value)?,
+                "p" => p_cost = parse(value)?,
+                "cipher" => {
+                    suite = CipherSuite::from_name(value)
+                        .ok_or_else(|| Error::invalid(format!("Unknown cipher suite {}", value)))?
+                }
+                key => return Err(Error::invalid(format!("Unknown option {}", key))),
+            }
+        }
+        if calibrate {
+            let params = Self::calibrate(DEFAULT_UNLOCK_TARGET)?;
+            m_cost = params.m_cost();
+            t_cost = params.t_cost();
+            p_cost = params.p_cost();
+        }
+        let mut ec = Self::with_params(m_cost, t_cost, p_cost)?;
+        ec.suite = suite;
+        Ok(ec)
+    }
+
+    /* Benchmarks Argon2id on this machine and picks parameters for which deriving a key takes
+     * about the target time. The memory cost is raised first since it is what makes attacks
+     * expensive, the iterations only once the memory cost is at its upper bound. On a machine
+     * where the defaults take longer than the target, the memory cost is lowered down to
+     * CALIBRATION_MIN_M_COST first and the iterations down to 1 after that */
+    pub fn calibrate(target: Duration) -> Result<Params> {
+        let p_cost = thread::available_parallelism()
+            .map(|n| n.get().min(4) as u32)
+            .unwrap_or(Params::DEFAULT_P_COST);
+        let mut t_cost = Params::DEFAULT_T_COST;
+        let mut m_cost = Params::DEFAULT_M_COST.max(8 * p_cost);
+
+        loop {
+            let elapsed = Self::with_params(m_cost, t_cost, p_cost)?.time_kdf()?;
+            // The time taken grows roughly linearly with both the memory cost and iterations
+            let ratio = target.as_secs_f64() / elapsed.as_secs_f64().max(0.001);
+            if ratio < 1.0 {
+                let min_m_cost = CALIBRATION_MIN_M_COST.max(8 * p_cost);
+                if m_cost > min_m_cost {
+                    m_cost = ((m_cost as f64 * ratio) as u32).max(min_m_cost);
+                } else if t_cost > 1 {
+                    t_cost = ((t_cost as f64 * ratio) as u32).max(1);
+                } else {
+                    break;
+                }
+                if ratio > 0.5 {
+                    break;
+                }
+                continue;
+            }
+            if ratio <= 1.0 {
+                break;
+            }
+            if m_cost < CALIBRATION_MAX_M_COST {
+                m_cost = ((m_cost as f64 * ratio) as u32).min(CALIBRATION_MAX_M_COST);
+            } else {
+                t_cost = ((t_cost as f64 * ratio).ceil() as u32).min(MAX_T_COST);
+            }
+            if ratio < 2.0 || t_cost == MAX_T_COST {
+                break;
+            }
+        }
+        Params::new(m_cost, t_cost, p_cost, None).map_err(|e| Error::invalid(e.to_string()))
+    }
+
+    /* Measures how long deriving a key with this scheme takes */
+    fn time_kdf(&self) -> Result<Duration> {
+        let mut out = [0u8; 32];
+        let start = Instant::now();
+        self.kdf
+            .hash_password_into(b"calibration", self.salt.as_bytes(), &mut out)
+            .map_err(|e| Error::invalid(e.to_string()))?;
+        Ok(start.elapsed())
+    }
+
+    /* Derives the key encryption key from the password, followed by the keyfile digest if the
+     * scheme requires a keyfile. A keyfile given for a scheme without one is ignored */
+    fn derive_key(&self, password: impl AsRef<[u8]>, keyfile: Option<&Keyfile>) -> Result<Key> {
+        let mut input = Zeroizing::new(password.as_ref().to_vec());
+        if let Some(check) = self.keyfile.as_ref() {
+            let keyfile = keyfile.ok_or(Error::KeyfileRequired)?;
+            if keyfile.check(&self.salt) != *check {
+                return Err(Error::WrongKeyfile);
+            }
+            input.extend_from_slice(keyfile.0.as_slice());
+        }
+        // Same derivation as hash_password, but writing straight into memory that is wiped
+        let mut salt = [0u8; 64];
+        let salt = self
+            .salt
+            .as_salt()
+            .b64_decode(&mut salt)
+            .map_err(|e| Error::corrupt(e.to_string()))?;
+        let mut key = Key::new([0; KEY_LEN]);
+        #[cfg(test)]
+        KDF_RUNS.with(|runs| runs.set(runs.get() + 1));
+        self.kdf
+            .hash_password_into(&input, salt, key.as_mut_slice())
+            .map_err(|e| Error::invalid(e.to_string()))?;
+        Ok(key)
+    }
+
+    /* Returns the data key the content is encrypted with. Legacy schemes without a wrapped key
+     * use the key derived from the password directly. */
+    pub fn unlock(&self, password: impl AsRef<[u8]>, keyfile: Option<&Keyfile>) -> Result<Key> {
+        let kek = self.derive_key(password, keyfile)?;
+        self.unlock_with_kek(&kek)
+    }
+
+    /* Verifies the key derived from the password against the check value. With a matching check
+     * value any later authentication failure means the file is corrupt. Legacy schemes have
+     * neither, only their content tells if the password was right */
+    fn unlock_with_kek(&self, kek: &Key) -> Result<Key> {
+        let Some(wrapped_key) = self.wrapped_key.as_ref() else {
+            return Ok(kek.clone());
         };
-        let plaintext = cipher
-            .decrypt(&self.nonce, payload)
-            .map_err(|E| io::Error::new(io::ErrorKind::Other, E.to_string()))?;
+        if self
+            .check
+            .as_ref()
+            .is_none_or(|check| key_check(kek) != *check)
+        {
+            return Err(Error::WrongPassword);
+        }
+        unwrap_key(self.suite, kek, wrapped_key).map_err(|e| match e {
+            Error::WrongPassword => Error::corrupt("Wrapped data key failed authentication"),
+            e => e,
+        })
+    }
 
-        return Ok(plaintext);
+    /* Returns the data key to encrypt with. Schemes without a wrapped key get a new random data
+     * key wrapped by the key derived from the password and the keyfile if one is given. */
+    pub fn unlock_or_generate(
+        &mut self,
+        password: impl AsRef<[u8]>,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Key> {
+        if self.wrapped_key.is_some() {
+            return self.unlock(password, keyfile);
+        }
+        self.keyfile = keyfile.map(|k| k.check(&self.salt));
+        let kek = self.derive_key(password, keyfile)?;
+        let mut key = Key::new([0; KEY_LEN]);
+        OsRng.fill_bytes(key.as_mut_slice());
+        self.wrapped_key = Some(wrap_key(self.suite, &kek, &key)?);
+        self.check = Some(key_check(&kek));
+        Ok(key)
     }
 
-    pub fn decrypt_file(
-        &self,
-        password: &str,
-        file: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut encrypted_content: Vec<u8> = Vec::new();
-        let mut f = File::open(file)?;
-        f.read_to_end(&mut encrypted_content)?;
-        let decrypted_content = self.decrypt(password, &encrypted_content, file.as_bytes())?;
-
-        let mut f = File::create(file)?;
-        f.write_all(&decrypted_content)?;
+    /* Wraps the data key with a key derived from the new password, the keyfile if one is given
+     * and a new salt. The old password has to be verified by unlocking the data key first. */
+    pub fn rewrap(
+        &mut self,
+        key: &Key,
+        new_password: impl AsRef<[u8]>,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<()> {
+        self.salt = SaltString::generate(&mut OsRng);
+        self.keyfile = keyfile.map(|k| k.check(&self.salt));
+        let kek = self.derive_key(new_password, keyfile)?;
+        self.wrapped_key = Some(wrap_key(self.suite, &kek, key)?);
+        self.check = Some(key_check(&kek));
         Ok(())
     }
-    pub fn encrypt_file(
+
+    /* Encrypts plaintext with the data key and a freshly generated nonce. The nonce is prepended
+     * to the returned ciphertext. */
+    pub fn encrypt_with_key(&self, key: &Key, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        seal_with_nonce(self.suite, key.as_slice(), plaintext, aad)
+    }
+
+    /* Decrypts ciphertext produced by encrypt_with_key. Legacy schemes still use the shared
+     * nonce. */
+    pub fn decrypt_with_key(&self, key: &Key, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        match self.nonce.as_ref() {
+            Some(nonce) => self
+                .suite
+                .cipher()
+                .decrypt(key.as_slice(), nonce.as_slice(), ciphertext, aad),
+            None => open_with_nonce(self.suite, key.as_slice(), ciphertext, aad),
+        }
+    }
+
+    /* Decrypts a module's ciphertext with its data key in memory and parses the resulting
+     * entries. The plaintext never touches the disk. */
+    pub fn decrypt_entries(
         &self,
-        password: &str,
-        file: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let ec = EncryptionScheme::default();
-        let mut f = File::open(file).expect(&format!("FAILED TO OPEN FILE {}", file));
-        let mut content = Vec::new();
-        f.read_to_end(&mut content)?;
-        let encrypted_content = self.encrypt(password, &content, file.as_bytes())?;
-        drop(f);
-
-        let mut f = File::create(file)?;
-        f.write_all(&encrypted_content)?;
-        Ok(())
+        key: &Key,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<PasswordEntries<'static>> {
+        let decrypted_content = Zeroizing::new(self.decrypt_with_key(key, ciphertext, aad)?);
+        payload::decode(&decrypted_content)
     }
 }
 
@@ -120,22 +396,45 @@ impl<'a> Serialize for EncryptionScheme<'a> {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("EncryptionScheme", 3)?;
-        let mut ph = match self.kdf.hash_password(b"", &self.salt) {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(ser::Error::custom(e.to_string()));
-            }
+        let len = 3
+            + self.nonce.is_some() as usize
+            + self.wrapped_key.is_some() as usize
+            + self.keyfile.is_some() as usize
+            + self.check.is_some() as usize;
+        let mut state = serializer.serialize_struct("EncryptionScheme", len)?;
+        // Only the parameters are written, hashing to get them would cost a key derivation
+        let ph = PasswordHash {
+            algorithm: self.algorithm.ident(),
+            version: Some(self.version.into()),
+            params: ParamsString::try_from(self.kdf.params())
+                .map_err(|e| ser::Error::custom(e.to_string()))?,
+            salt: Some(self.salt.as_salt()),
+            hash: None,
         };
-        ph.hash = None;
         state.serialize_field("kdf", &ph.to_string())?;
         state.serialize_field("salt", self.salt.as_str())?;
-        state.serialize_field("nonce", self.nonce.as_slice())?;
+        match self.nonce.as_ref() {
+            Some(nonce) => state.serialize_field("nonce", nonce.as_slice())?,
+            None => state.skip_field("nonce")?,
+        }
+        match self.wrapped_key.as_ref() {
+            Some(wrapped_key) => state.serialize_field("key", wrapped_key)?,
+            None => state.skip_field("key")?,
+        }
+        state.serialize_field("cipher", self.suite.name())?;
+        match self.keyfile.as_ref() {
+            Some(check) => state.serialize_field("keyfile", check)?,
+            None => state.skip_field("keyfile")?,
+        }
+        match self.check.as_ref() {
+            Some(check) => state.serialize_field("check", check)?,
+            None => state.skip_field("check")?,
+        }
         state.end()
     }
 }
 
-impl<'de: 'a, 'a> Deserialize<'de> for EncryptionScheme<'a> {
+impl<'de, 'a> Deserialize<'de> for EncryptionScheme<'a> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
@@ -146,12 +445,17 @@ impl<'de: 'a, 'a> Deserialize<'de> for EncryptionScheme<'a> {
             Kdf,
             Salt,
             Nonce,
+            Key,
+            Cipher,
+            Keyfile,
+            Check,
         }
 
-        struct SchemeVisitor;
+        // The scheme owns all of its data so it does not borrow from the deserializer input
+        struct SchemeVisitor<'a>(PhantomData<EncryptionScheme<'a>>);
 
-        impl<'de> Visitor<'de> for SchemeVisitor {
-            type Value = EncryptionScheme<'de>;
+        impl<'de, 'a> Visitor<'de> for SchemeVisitor<'a> {
+            type Value = EncryptionScheme<'a>;
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
                 formatter.write_str("struct EncryptionScheme")
             }
@@ -163,6 +467,10 @@ impl<'de: 'a, 'a> Deserialize<'de> for EncryptionScheme<'a> {
                 let mut kdf = None;
                 let mut salt = None;
                 let mut nonce = None;
+                let mut wrapped_key = None;
+                let mut suite = None;
+                let mut keyfile = None;
+                let mut check = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -173,49 +481,121 @@ impl<'de: 'a, 'a> Deserialize<'de> for EncryptionScheme<'a> {
                             let val: &str = map.next_value()?;
                             let ph_string = match PasswordHashString::new(val) {
                                 Ok(v) => v,
-                                Err(e) => return Err(de::Error::custom("Invalid phc string")),
+                                Err(_) => return Err(de::Error::custom("Invalid phc string")),
                             };
                             let ph: PasswordHash = ph_string.password_hash();
-                            let params: Params = Params::try_from(&ph).unwrap();
-                            let version: Version = ph.version.unwrap().try_into().unwrap();
-                            let arg = Argon2::new(
-                                Algorithm::new(ph.algorithm.as_str()).unwrap(),
-                                version,
-                                params,
-                            );
-                            kdf = Some(arg);
+                            let params: Params = Params::try_from(&ph).map_err(|e| {
+                                de::Error::custom(format!("Invalid kdf parameters: {}", e))
+                            })?;
+                            check_params(&params).map_err(de::Error::custom)?;
+                            let version = ph
+                                .version
+                                .ok_or_else(|| de::Error::missing_field("kdf version"))?;
+                            let version = Version::try_from(version).map_err(|e| {
+                                de::Error::custom(format!("Invalid kdf version: {}", e))
+                            })?;
+                            let algorithm = Algorithm::new(ph.algorithm.as_str()).map_err(|e| {
+                                de::Error::custom(format!("Invalid kdf algorithm: {}", e))
+                            })?;
+                            let arg = Argon2::new(algorithm, version, params);
+                            kdf = Some((arg, algorithm, version));
                         }
                         Field::Salt => {
                             if salt.is_some() {
                                 return Err(de::Error::duplicate_field("salt"));
                             }
                             let val: &str = map.next_value()?;
-                            let salt_string = SaltString::new(val).ok();
-                            salt = salt_string;
+                            let salt_string = SaltString::new(val)
+                                .map_err(|e| de::Error::custom(format!("Invalid salt: {}", e)))?;
+                            salt = Some(salt_string);
                         }
                         Field::Nonce => {
                             if nonce.is_some() {
                                 return Err(de::Error::duplicate_field("nonce"));
                             }
-                            let val: Vec<u8> = map.next_value().expect("FAILED");
-                            let arr: [u8; 12] = val.try_into().expect("Wrong nonce length");
+                            let val: Vec<u8> = map.next_value()?;
+                            let arr: [u8; NONCE_LEN] = val.try_into().map_err(|val: Vec<u8>| {
+                                de::Error::invalid_length(val.len(), &"a 12 byte nonce")
+                            })?;
                             let val = Nonce::from(arr);
                             nonce = Some(val);
                         }
+                        Field::Key => {
+                            if wrapped_key.is_some() {
+                                return Err(de::Error::duplicate_field("key"));
+                            }
+                            let val: Vec<u8> = map.next_value()?;
+                            wrapped_key = Some(val);
+                        }
+                        Field::Cipher => {
+                            if suite.is_some() {
+                                return Err(de::Error::duplicate_field("cipher"));
+                            }
+                            let val: &str = map.next_value()?;
+                            let val = CipherSuite::from_name(val).ok_or_else(|| {
+                                de::Error::custom(format!("Unsupported cipher suite {}", val))
+                            })?;
+                            suite = Some(val);
+                        }
+                        Field::Keyfile => {
+                            if keyfile.is_some() {
+                                return Err(de::Error::duplicate_field("keyfile"));
+                            }
+                            let val: Vec<u8> = map.next_value()?;
+                            if val.len() != KEYFILE_CHECK_LEN {
+                                return Err(de::Error::invalid_length(
+                                    val.len(),
+                                    &"a keyfile check value",
+                                ));
+                            }
+                            keyfile = Some(val);
+                        }
+                        Field::Check => {
+                            if check.is_some() {
+                                return Err(de::Error::duplicate_field("check"));
+                            }
+                            let val: Vec<u8> = map.next_value()?;
+                            if val.len() != KEY_CHECK_LEN {
+                                return Err(de::Error::invalid_length(
+                                    val.len(),
+                                    &"a key check value",
+                                ));
+                            }
+                            check = Some(val);
+                        }
                     }
                 }
-                let kdf = kdf.ok_or_else(|| de::Error::missing_field("kdf"))?;
+                let (kdf, algorithm, version) =
+                    kdf.ok_or_else(|| de::Error::missing_field("kdf"))?;
                 let salt = salt.ok_or_else(|| de::Error::missing_field("salt"))?;
-                let nonce = nonce.ok_or_else(|| de::Error::missing_field("nonce"))?;
+                // Schemes written before suites were recorded all use ChaCha20Poly1305
+                let suite = suite.unwrap_or(CipherSuite::ChaCha20Poly1305);
+                if let Some(val) = wrapped_key.as_ref() {
+                    if val.len() != wrapped_key_len(suite) {
+                        return Err(de::Error::invalid_length(val.len(), &"a wrapped data key"));
+                    }
+                    if check.is_none() {
+                        return Err(de::Error::missing_field("check"));
+                    }
+                }
+                if nonce.is_some() && suite != CipherSuite::ChaCha20Poly1305 {
+                    return Err(de::Error::custom("Shared nonce used with a new cipher suite"));
+                }
                 Ok(EncryptionScheme {
                     kdf,
+                    algorithm,
+                    version,
                     salt,
                     nonce: nonce,
+                    wrapped_key,
+                    suite,
+                    keyfile,
+                    check,
                 })
             }
         }
-        const FIELDS: &'static [&'static str] = &["kdf", "salt", "nonce"];
-        deserializer.deserialize_struct("EncryptionScheme", FIELDS, SchemeVisitor)
+        const FIELDS: &[&str] = &["kdf", "salt", "nonce", "key", "cipher", "keyfile", "check"];
+        deserializer.deserialize_struct("EncryptionScheme", FIELDS, SchemeVisitor(PhantomData))
     }
 }
 
@@ -223,8 +603,280 @@ impl<'a> Default for EncryptionScheme<'a> {
     fn default() -> Self {
         Self {
             kdf: Argon2::default(),
+            algorithm: Algorithm::default(),
+            version: Version::default(),
             salt: SaltString::generate(&mut OsRng),
-            nonce: ChaCha20Poly1305::generate_nonce(&mut OsRng),
+            nonce: None,
+            wrapped_key: None,
+            suite: CipherSuite::default(),
+            keyfile: None,
+            check: None,
+        }
+    }
+}
+
+/* Encrypts with a fresh random nonce that is prepended to the ciphertext */
+fn seal_with_nonce(
+    suite: CipherSuite,
+    key: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = suite.cipher();
+    let mut content = vec![0u8; cipher.nonce_len()];
+    OsRng.fill_bytes(&mut content);
+    let encrypted_content = cipher.encrypt(key, &content, plaintext, aad)?;
+    content.extend_from_slice(&encrypted_content);
+    Ok(content)
+}
+
+/* Decrypts ciphertext produced by seal_with_nonce */
+fn open_with_nonce(
+    suite: CipherSuite,
+    key: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = suite.cipher();
+    if ciphertext.len() < cipher.nonce_len() + TAG_LEN {
+        return Err(Error::corrupt("Ciphertext too short"));
+    }
+    let (nonce, ciphertext) = ciphertext.split_at(cipher.nonce_len());
+    cipher.decrypt(key, nonce, ciphertext, aad)
+}
+
+/* Length of a data key wrapped with the suite: nonce, encrypted key and tag */
+fn wrapped_key_len(suite: CipherSuite) -> usize {
+    suite.cipher().nonce_len() + KEY_LEN + TAG_LEN
+}
+
+/* Encrypts the data key with the key encryption key */
+fn wrap_key(suite: CipherSuite, kek: &Key, key: &Key) -> Result<Vec<u8>> {
+    seal_with_nonce(suite, kek.as_slice(), key.as_slice(), WRAP_AAD)
+}
+
+/* Decrypts a data key wrapped by wrap_key. Fails if the key encryption key is wrong */
+fn unwrap_key(suite: CipherSuite, kek: &Key, wrapped_key: &[u8]) -> Result<Key> {
+    if wrapped_key.len() != wrapped_key_len(suite) {
+        return Err(Error::corrupt("Wrapped key has the wrong length"));
+    }
+    // The wrapped key only fails to authenticate if the key derived from the password is wrong
+    let unwrapped = Zeroizing::new(
+        open_with_nonce(suite, kek.as_slice(), wrapped_key, WRAP_AAD)
+            .map_err(|_| Error::WrongPassword)?,
+    );
+    if unwrapped.len() != KEY_LEN {
+        return Err(Error::corrupt("Unwrapped key has the wrong length"));
+    }
+    let mut key = Key::new([0; KEY_LEN]);
+    key.copy_from_slice(&unwrapped);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /* Serialized scheme with every field a written module has */
+    fn serialized_scheme() -> Vec<u8> {
+        let mut ec = EncryptionScheme::with_params(8, 1, 1).unwrap();
+        ec.unlock_or_generate("password", None).unwrap();
+        ron::ser::to_string(&ec).unwrap().into_bytes()
+    }
+
+    fn unlock(content: &[u8]) -> Result<Key> {
+        ron::de::from_bytes::<EncryptionScheme>(content)?.unlock("password", None)
+    }
+
+    #[test]
+    fn scheme_is_written_without_deriving_a_key() {
+        let ec = EncryptionScheme::with_params(8, 1, 1).unwrap();
+        let runs = KDF_RUNS.with(|runs| runs.get());
+        let content = ron::ser::to_string(&ec).unwrap();
+        assert_eq!(KDF_RUNS.with(|runs| runs.get()), runs);
+        let kdf = format!("(kdf:\"$argon2id$v=19$m=8,t=1,p=1${}\",", ec.salt.as_str());
+        assert!(content.starts_with(&kdf), "{}", content);
+    }
+
+    /* Writes a keyfile for a test and returns its path */
+    fn write_keyfile(name: &str, content: &[u8]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("pwmanager-{}-{}.key", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn keyfile_is_required() {
+        let keyfile = Keyfile::read(&write_keyfile("required", b"first keyfile")).unwrap();
+        let other = Keyfile::read(&write_keyfile("other", b"second keyfile")).unwrap();
+        let mut ec = EncryptionScheme::with_params(8, 1, 1).unwrap();
+        let key = ec.unlock_or_generate("password", Some(&keyfile)).unwrap();
+        let ec: EncryptionScheme = ron::de::from_str(&ron::ser::to_string(&ec).unwrap()).unwrap();
+
+        assert_eq!(ec.unlock("password", Some(&keyfile)).unwrap(), key);
+        assert!(matches!(
+            ec.unlock("password", None),
+            Err(Error::KeyfileRequired)
+        ));
+        assert!(matches!(
+            ec.unlock("password", Some(&other)),
+            Err(Error::WrongKeyfile)
+        ));
+        assert!(matches!(
+            ec.unlock("wrong", Some(&keyfile)),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn keyfile_is_ignored_without_one() {
+        let keyfile = Keyfile::read(&write_keyfile("ignored", b"keyfile")).unwrap();
+        let mut ec = EncryptionScheme::with_params(8, 1, 1).unwrap();
+        let key = ec.unlock_or_generate("password", None).unwrap();
+        assert_eq!(ec.unlock("password", Some(&keyfile)).unwrap(), key);
+    }
+
+    #[test]
+    fn empty_or_missing_keyfile_is_rejected() {
+        assert!(matches!(
+            Keyfile::read(&write_keyfile("empty", b"")),
+            Err(Error::Invalid(_))
+        ));
+        let missing = write_keyfile("missing", b"");
+        fs::remove_file(&missing).unwrap();
+        assert!(matches!(Keyfile::read(&missing), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let ec = EncryptionScheme::from_options("").unwrap();
+        assert_eq!(ec.suite, CipherSuite::XChaCha20Poly1305);
+        assert_eq!(ec.kdf.params().m_cost(), Params::DEFAULT_M_COST);
+        assert_eq!(ec.kdf.params().t_cost(), Params::DEFAULT_T_COST);
+
+        let ec = EncryptionScheme::from_options(" m=8192, t=2,p=1 , cipher=aes").unwrap();
+        assert_eq!(ec.suite, CipherSuite::Aes256GcmSiv);
+        let params = ec.kdf.params();
+        assert_eq!(
+            (params.m_cost(), params.t_cost(), params.p_cost()),
+            (8192, 2, 1)
+        );
+        // Options that are left out keep their default
+        let ec = EncryptionScheme::from_options("cipher=chacha20poly1305,t=3").unwrap();
+        assert_eq!(ec.suite, CipherSuite::ChaCha20Poly1305);
+        assert_eq!(ec.kdf.params().m_cost(), Params::DEFAULT_M_COST);
+        assert_eq!(ec.kdf.params().t_cost(), 3);
+        let ec = EncryptionScheme::from_options("cipher=xchacha").unwrap();
+        assert_eq!(ec.suite, CipherSuite::XChaCha20Poly1305);
+
+        for options in ["cipher=des", "m=abc", "t=-1", "x=1", "m", "m=4,p=1", "t=0"] {
+            assert!(
+                EncryptionScheme::from_options(options).is_err(),
+                "{}",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn costly_parameters_are_rejected() {
+        let scheme = |m: u32, t: u32, p: u32| {
+            format!(
+                "(kdf:\"$argon2id$v=19$m={},t={},p={}$c2FsdHNhbHQ\",salt:\"c2FsdHNhbHQ\")",
+                m, t, p
+            )
+        };
+        let parse = |content: String| ron::de::from_bytes::<EncryptionScheme>(content.as_bytes());
+        parse(scheme(CALIBRATION_MAX_M_COST, MAX_T_COST, MAX_P_COST)).unwrap();
+        assert!(parse(scheme(CALIBRATION_MAX_M_COST + 1, 1, 1)).is_err());
+        assert!(parse(scheme(0x0fff_ffff, 1, 1)).is_err());
+        assert!(parse(scheme(1024, MAX_T_COST + 1, 1)).is_err());
+        assert!(parse(scheme(1024, u32::MAX, 1)).is_err());
+        assert!(parse(scheme(1024, 1, MAX_P_COST + 1)).is_err());
+        assert!(EncryptionScheme::from_options("m=2097152").is_err());
+
+        // Whatever parses stays within the bounds
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            let mut cost = |max: u32| match rng.gen_range(0..3) {
+                0 => rng.gen_range(0..=max),
+                1 => rng.gen_range(max..=max.saturating_mul(2)),
+                _ => rng.gen(),
+            };
+            let (m, t, p) = (
+                cost(CALIBRATION_MAX_M_COST),
+                cost(MAX_T_COST),
+                cost(MAX_P_COST),
+            );
+            if let Ok(ec) = parse(scheme(m, t, p)) {
+                let params = ec.kdf.params();
+                assert!(params.m_cost() <= CALIBRATION_MAX_M_COST);
+                assert!(params.t_cost() <= MAX_T_COST);
+                assert!(params.p_cost() <= MAX_P_COST);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_scheme_is_rejected() {
+        let content = serialized_scheme();
+        unlock(&content).unwrap();
+        for len in 0..content.len() {
+            assert!(ron::de::from_bytes::<EncryptionScheme>(&content[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn bit_flipped_scheme_fails_to_unlock() {
+        // Flips that still parse change the parameters, the salt or the wrapped key. A few are
+        // ignored by the parser, e.g. in the copy of the salt in the PHC string, and read back
+        // as the same scheme
+        let content = serialized_scheme();
+        for i in 0..content.len() {
+            for bit in 0..8 {
+                let mut flipped = content.clone();
+                flipped[i] ^= 1 << bit;
+                let same = ron::de::from_bytes::<EncryptionScheme>(&flipped).is_ok_and(|ec| {
+                    ron::ser::to_string(&ec).is_ok_and(|s| s.as_bytes() == content)
+                });
+                assert!(
+                    same || unlock(&flipped).is_err(),
+                    "flipped bit {} of byte {}",
+                    bit,
+                    i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn random_scheme_is_rejected() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            let mut content = vec![0u8; rng.gen_range(0..256)];
+            rng.fill(content.as_mut_slice());
+            assert!(ron::de::from_bytes::<EncryptionScheme>(&content).is_err());
+        }
+        // Random values in the fields get past the RON syntax
+        for _ in 0..2000 {
+            let mut field = || -> String {
+                let len = rng.gen_range(0..40);
+                (0..len)
+                    .map(|_| rng.gen_range(0x20u8..0x7f) as char)
+                    .collect()
+            };
+            let content = format!(
+                "(kdf:\"{}\",salt:\"{}\",key:[{}],cipher:\"{}\")",
+                field().replace('"', ""),
+                field().replace('"', ""),
+                field(),
+                field().replace('"', "")
+            );
+            assert!(unlock(content.as_bytes()).is_err(), "{}", content);
         }
     }
 }
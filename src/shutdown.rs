@@ -0,0 +1,67 @@
+/*
+ * Leaving the app cleanly when it doesn't quit through q. A panic or a SIGTERM, SIGHUP or SIGINT
+ * would otherwise skip clean_up, leaving modules encrypted in memory as plaintext on disk and the
+ * terminal in raw mode.
+ *
+ * The signal handlers only set a flag, the event loop sees it within POLL_INTERVAL and quits
+ * like q does. A second signal ends the process right away, in case saving hangs. The panic
+ * hook restores the terminal so the panic message is readable, run_app then saves the vault
+ * before the panic continues.
+ * */
+
+use std::io;
+use std::panic;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossterm::{
+    cursor::Show,
+    event::DisableMouseCapture,
+    execute,
+    terminal::{disable_raw_mode, LeaveAlternateScreen},
+};
+
+/* How long the event loop waits for input before checking for signals */
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/* Registers the signal handlers. The returned flag is set once a signal asks the app to exit */
+#[cfg(unix)]
+pub fn register_signals() -> io::Result<Arc<AtomicBool>> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::flag;
+
+    let exit = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGHUP, SIGINT] {
+        // Exits right away if the flag is already set, i.e. on the second signal
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(&exit))?;
+        flag::register(signal, Arc::clone(&exit))?;
+    }
+    Ok(exit)
+}
+
+/* Other platforms don't get these signals, the app is ended by closing its console */
+#[cfg(not(unix))]
+pub fn register_signals() -> io::Result<Arc<AtomicBool>> {
+    Ok(Arc::new(AtomicBool::new(false)))
+}
+
+/* Leaves raw mode and the alternate screen */
+pub fn restore_terminal() -> io::Result<()> {
+    disable_raw_mode()?;
+    execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        Show
+    )
+}
+
+/* Restores the terminal before the panic message is printed */
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        default_hook(info);
+    }));
+}
@@ -0,0 +1,249 @@
+/*
+ * Storage of the vault files. Paths are relative to the vault and separated by '/', e.g.
+ * "General.json" or "backups/General/<timestamp>.json". Every module, keyring and backup file is
+ * read and written through a VaultStore, so the same logic runs on the pwmanager directory and on
+ * a vault kept in memory.
+ * */
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::error::Result;
+use crate::lock::VaultLock;
+use crate::password::{remove_durable, replace_plaintext, secure_remove, write_atomic};
+
+pub trait VaultStore {
+    /* Reads a file. Fails with io::ErrorKind::NotFound if there is none */
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    /* Replaces the content of a file, creating it and its directories if needed. A crash leaves
+     * either the old or the new content */
+    fn write(&self, path: &str, content: &[u8]) -> io::Result<()>;
+
+    fn remove(&self, path: &str) -> io::Result<()>;
+
+    /* Writes like write over a file that held plaintext, which is wiped. Stores outside the
+     * file system leave nothing behind to wipe */
+    fn replace_plaintext(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        self.write(path, content)
+    }
+
+    /* Removes like remove a file that held plaintext, which is wiped first */
+    fn secure_remove(&self, path: &str) -> io::Result<()> {
+        self.remove(path)
+    }
+
+    /* Checks if there is a file or directory at path */
+    fn exists(&self, path: &str) -> io::Result<bool>;
+
+    /* Names of the files directly in the directory dir, "" being the vault itself. Fails with
+     * io::ErrorKind::NotFound if there is no such directory */
+    fn list(&self, dir: &str) -> io::Result<Vec<String>>;
+
+    /* Moves a file or a directory with everything in it */
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /* Absolute path of a file, which legacy module files are bound to by their AAD. Stores
+     * outside the file system have none */
+    fn legacy_path(&self, _path: &str) -> Option<String> {
+        None
+    }
+
+    /* Checks if the vault was opened read-only, its files are then never written */
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/* Vault in a directory of the file system. It is locked while open, unless it is read-only */
+pub struct FsStore {
+    root: PathBuf,
+    lock: Option<VaultLock>,
+}
+
+impl FsStore {
+    /* Opens the vault in the directory root, creating it if needed. Fails with Error::Locked if
+     * another instance has the vault open for writing */
+    pub fn new(root: PathBuf, read_only: bool) -> Result<Self> {
+        fs::create_dir_all(&root)?;
+        let lock = if read_only {
+            None
+        } else {
+            Some(VaultLock::acquire(&root)?)
+        };
+        Ok(Self { root, lock })
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.lock.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "The vault is open read-only",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl VaultStore for FsStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(path))
+    }
+
+    fn write(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        let path = self.path(path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(&path, content)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.check_writable()?;
+        remove_durable(&self.path(path))
+    }
+
+    fn replace_plaintext(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        replace_plaintext(&self.path(path), content)
+    }
+
+    fn secure_remove(&self, path: &str) -> io::Result<()> {
+        self.check_writable()?;
+        secure_remove(&self.path(path))
+    }
+
+    fn exists(&self, path: &str) -> io::Result<bool> {
+        self.path(path).try_exists()
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        for entry in self.path(dir).read_dir()? {
+            let entry = entry?;
+            // Names that aren't valid UTF-8 can't belong to the vault
+            if entry.file_type()?.is_file() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    files.push(name);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.check_writable()?;
+        fs::rename(self.path(from), self.path(to))
+    }
+
+    fn legacy_path(&self, path: &str) -> Option<String> {
+        Some(self.path(path).to_string_lossy().into_owned())
+    }
+
+    fn read_only(&self) -> bool {
+        self.lock.is_none()
+    }
+}
+
+/* Vault kept in memory, e.g. to try out operations without touching the real vault */
+#[derive(Default)]
+pub struct MemoryStore {
+    files: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))
+}
+
+/* Prefix of the paths of everything in the directory dir */
+fn dir_prefix(dir: &str) -> String {
+    if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", dir.trim_end_matches('/'))
+    }
+}
+
+impl VaultStore for MemoryStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.files
+            .borrow()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn write(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        self.files
+            .borrow_mut()
+            .insert(path.to_owned(), content.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.files
+            .borrow_mut()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn exists(&self, path: &str) -> io::Result<bool> {
+        let prefix = dir_prefix(path);
+        let files = self.files.borrow();
+        Ok(files.contains_key(path) || files.keys().any(|p| p.starts_with(&prefix)))
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        if !self.exists(dir)? && !dir.is_empty() {
+            return Err(not_found(dir));
+        }
+        let prefix = dir_prefix(dir);
+        Ok(self
+            .files
+            .borrow()
+            .keys()
+            .filter_map(|p| p.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        if let Some(content) = files.remove(from) {
+            files.insert(to.to_owned(), content);
+            return Ok(());
+        }
+        // Directories are moved with every file in them
+        let (from, to) = (dir_prefix(from), dir_prefix(to));
+        let moved: Vec<String> = files
+            .keys()
+            .filter(|p| p.starts_with(&from))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Err(not_found(&from));
+        }
+        for path in moved {
+            if let Some(content) = files.remove(&path) {
+                files.insert(format!("{}{}", to, &path[from.len()..]), content);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
-use std::fs::File;
-use std::path::{Path, PathBuf};
-use std::{env, io};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 //use clipboard::ClipboardContext;
 //use clipboard::ClipboardProvider;
@@ -20,10 +21,19 @@ use tui::{
     widgets::*,
     Frame, Terminal,
 };
+use zeroize::{Zeroize, Zeroizing};
 
-use crate::password::{read_from_file, ModuleList, Password, PasswordEntries, HOME_ENV};
+use crate::error::{Error, Result};
+use crate::keyring::Keyring;
+use crate::password::{
+    new_hidden_id, read_module_file, ModuleList, Password, PasswordEntries, ENCRYPTIONS_FILE,
+};
 
-use crate::pbes::EncryptionScheme;
+use crate::payload;
+use crate::pbes::{EncryptionScheme, Key, Keyfile};
+use crate::shutdown::POLL_INTERVAL;
+use crate::store::{FsStore, VaultStore};
+use crate::vaults::Vaults;
 use ron;
 
 /* Struct to hold UI data */
@@ -33,15 +43,25 @@ pub struct ModuleUI<'a> {
     table_state: TableState,
     table_key: Option<Cow<'a, str>>,
     display_module: bool,
-    input_string: String,
+    input_string: Zeroizing<String>,
     module_index: Option<usize>,
     selection: Selection,
     input_mode: InputMode,
     input_to: InputTo,
-    passwords: HashMap<Cow<'a, str>, String>,
+    /* Data keys of the unlocked encrypted modules. The passwords are never kept */
+    keys: HashMap<Cow<'a, str>, Key>,
+    /* Vault keyring once it is unlocked with the master password */
+    keyring: Option<Keyring>,
+    /* Registered vaults and the name or directory of the open vault */
+    vaults: Vaults,
+    vault: String,
     display_error: bool,
     error_message: String,
 }
+/* Capacity reserved for typed input. Growing the buffer would leave copies of typed passwords
+ * behind in freed memory */
+const INPUT_CAPACITY: usize = 256;
+
 /* Input Mode of the UI*/
 #[derive(PartialEq, Eq)]
 enum InputMode {
@@ -54,6 +74,11 @@ enum InputTo {
     Nothing,
     Decrypt,
     Encrypt,
+    ChangePassword,
+    Vault,
+    CreateVault,
+    OpenVault,
+    Recover,
     Module,
     Password,
     Add,
@@ -66,23 +91,76 @@ enum Selection {
 
 /* Methods for the UI*/
 impl<'a> ModuleUI<'a> {
-    pub fn new(module_list: ModuleList<'a>) -> Self {
+    pub fn new(module_list: ModuleList<'a>, vaults: Vaults, vault: String) -> Self {
         Self {
             module_list,
             state: ListState::default(),
             table_state: TableState::default(),
             table_key: None,
             display_module: false,
-            input_string: String::new(),
+            input_string: Zeroizing::new(String::with_capacity(INPUT_CAPACITY)),
             module_index: None,
             selection: Selection::Modules,
             input_mode: InputMode::Normal,
             input_to: InputTo::Nothing,
-            passwords: HashMap::new(),
+            keys: HashMap::new(),
+            keyring: None,
+            vaults,
+            vault,
             display_error: false,
             error_message: String::new(),
         }
     }
+    /* Shows the error. The prompt stays open after a wrong password or keyfile so it can be
+     * typed again, any other error closes it */
+    fn show_error(&mut self, e: Error) {
+        if e.is_wrong_credentials() {
+            self.input_mode = InputMode::Inputing;
+        } else {
+            self.input_to = InputTo::Nothing;
+        }
+        self.display_error = true;
+        self.error_message = e.to_string();
+    }
+    /* Asks for the vault master password, twice if it creates the keyring */
+    pub fn prompt_vault(&mut self) {
+        self.input_mode = InputMode::Inputing;
+        self.input_to = match Keyring::exists(&*self.module_list.store) {
+            Ok(false) => InputTo::CreateVault,
+            _ => InputTo::Vault,
+        };
+    }
+    /* Warns about modules left decrypted on disk and asks for the password of the first one to
+     * encrypt it again. Returns false if there are none */
+    pub fn prompt_recover(&mut self) -> bool {
+        let list = &self.module_list;
+        let exposed: Vec<_> = list
+            .modules
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| list.exposed.contains(&m.0))
+            .map(|(i, m)| (i, list.display_name(&m.0).into_owned()))
+            .collect();
+        let index = match exposed.first() {
+            Some((index, _)) => *index,
+            None => return false,
+        };
+        self.error_message = format!(
+            "WARNING: found modules decrypted on disk: {}\nTheir passwords were stored in \
+             plaintext and may have been copied, consider changing them",
+            exposed
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        self.display_error = true;
+        self.state.select(Some(index));
+        self.module_index = Some(index);
+        self.input_mode = InputMode::Inputing;
+        self.input_to = InputTo::Recover;
+        true
+    }
     /* Implements selection of passwords*/
     pub fn next_password(&mut self) {
         if let Some(i) = self.module_index {
@@ -133,8 +211,11 @@ impl<'a> ModuleUI<'a> {
         self.module_list = items;
         self.state = ListState::default();
     }
-    /* Implements selection of modules */
+    /* Implements selection of modules. Read-only vaults can have none */
     pub fn next(&mut self) {
+        if self.module_list.modules.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i >= self.module_list.modules.len() - 1 {
@@ -150,6 +231,9 @@ impl<'a> ModuleUI<'a> {
 
     // Select the previous item.
     pub fn previous(&mut self) {
+        if self.module_list.modules.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
@@ -171,13 +255,19 @@ impl<'a> ModuleUI<'a> {
 }
 /* Draw the list of modules */
 fn draw_module_list<B: Backend>(f: &mut Frame<B>, area: Rect, app: &mut ModuleUI) {
-    let block = Block::default().title("Modules").borders(Borders::ALL);
+    let block = Block::default()
+        .title(if app.module_list.store.read_only() {
+            format!("Modules ({}, read-only)", app.vault)
+        } else {
+            format!("Modules ({})", app.vault)
+        })
+        .borders(Borders::ALL);
 
     let items: Vec<ListItem> = app
         .module_list
         .modules
         .iter()
-        .map(|(name, _)| ListItem::new(name.as_ref()))
+        .map(|(name, _)| ListItem::new(app.module_list.display_name(name)))
         .enumerate()
         .map(|(i, l)| {
             let style = match app.module_index {
@@ -210,9 +300,31 @@ fn draw_input_prompt<B: Backend>(f: &mut Frame<B>, area: Rect, app: &mut ModuleU
         .margin(1)
         .constraints([Constraint::Length(2), Constraint::Length(2)].as_ref())
         .split(area);
+    let open_vault_text;
     let text = match app.input_to {
-        InputTo::Decrypt => "Type in the password to decrypt module",
-        InputTo::Encrypt => "Type in password to encrypt module with",
+        InputTo::Decrypt => "Type in the password (Tab) keyfile if needed to decrypt module",
+        InputTo::Encrypt => {
+            "Type password (Tab) options: auto or m=KiB,t=N,p=N, cipher=xchacha|aes, keyfile=path"
+        }
+        InputTo::ChangePassword => {
+            "Type old password (Tab) new password (Tab) keyfile if needed of the module"
+        }
+        InputTo::Vault => "Type the vault master password",
+        InputTo::CreateVault => {
+            "The vault has no keyring yet. Type a master password (Tab) the password again to \
+             create it"
+        }
+        InputTo::OpenVault => {
+            open_vault_text = format!(
+                "Type vault name or directory (Tab) directory to register the name. Vaults: {}",
+                app.vaults.names().collect::<Vec<_>>().join(", ")
+            );
+            &open_vault_text
+        }
+        InputTo::Recover => {
+            "Module was left DECRYPTED on disk. Type its password (Tab) the password again to \
+             encrypt it right away"
+        }
         InputTo::Module => "Type the name of the module",
         InputTo::Password => "Type in name/description of password",
         InputTo::Add => "Type name (Tab) password to import password",
@@ -227,40 +339,33 @@ fn draw_input_prompt<B: Backend>(f: &mut Frame<B>, area: Rect, app: &mut ModuleU
     f.render_widget(Clear, area);
     f.render_widget(block, area);
     f.render_widget(paragraph, chunks[0]);
-    // Need  to handle case of manual password differently since it needs two inputs
-    if app.input_to == InputTo::Add {
+    // Need  to handle prompts with several inputs differently, the inputs are separated by tabs
+    let titles = input_fields(&app.input_to);
+    if !titles.is_empty() {
         let input_chunks = Layout::default()
             .direction(Direction::Horizontal)
-            .constraints([Constraint::Length(15), Constraint::Length(20)].as_ref())
+            .constraints(
+                titles
+                    .iter()
+                    .enumerate()
+                    .map(|(i, _)| Constraint::Length(if i == 0 { 15 } else { 20 }))
+                    .collect::<Vec<_>>(),
+            )
             .split(chunks[1]);
-        let mut x_coord = 0_u16;
         let y_coord = input_chunks[0].y + 1;
-        let mut iter = app.input_string.split_whitespace();
-        let first_input = if let Some(first) = iter.next() {
-            x_coord = input_chunks[0].x + first.len() as u16 + 1;
-            Paragraph::new(first.as_ref())
-                .style(Style::default().fg(Color::Yellow))
-                .block(Block::default().borders(Borders::all()).title("Name"))
-        } else {
-            x_coord = input_chunks[0].x + 1;
-            Paragraph::new("")
-                .style(Style::default().fg(Color::Yellow))
-                .block(Block::default().borders(Borders::all()).title("Name"))
-        };
-
-        let second_input = if let Some(second) = iter.next() {
-            x_coord = input_chunks[1].x + second.len() as u16;
-            Paragraph::new(second.get(1..).unwrap_or(""))
-                .style(Style::default().fg(Color::Yellow))
-                .block(Block::default().borders(Borders::all()).title("Password"))
-        } else {
-            Paragraph::new("")
-                .style(Style::default().fg(Color::Yellow))
-                .block(Block::default().borders(Borders::all()).title("Password"))
-        };
+        let mut x_coord = input_chunks[0].x + 1;
         f.render_widget(Clear, chunks[1]);
-        f.render_widget(first_input, input_chunks[0]);
-        f.render_widget(second_input, input_chunks[1]);
+        let mut inputs = app.input_string.split('\t');
+        for (i, title) in titles.iter().enumerate() {
+            let input = inputs.next();
+            if let Some(input) = input {
+                x_coord = input_chunks[i].x + input.len() as u16 + 1;
+            }
+            let paragraph = Paragraph::new(input.unwrap_or(""))
+                .style(Style::default().fg(Color::Yellow))
+                .block(Block::default().borders(Borders::all()).title(*title));
+            f.render_widget(paragraph, input_chunks[i]);
+        }
         f.set_cursor(x_coord, y_coord);
     } else {
         let input = Paragraph::new(app.input_string.as_ref())
@@ -276,6 +381,52 @@ fn draw_input_prompt<B: Backend>(f: &mut Frame<B>, area: Rect, app: &mut ModuleU
     }
 }
 
+/* Titles of the inputs of prompts with several inputs. Empty for prompts with a single input */
+fn input_fields(input_to: &InputTo) -> &'static [&'static str] {
+    match input_to {
+        InputTo::Add => &["Name", "Password"],
+        InputTo::Decrypt => &["Password", "Keyfile"],
+        InputTo::Encrypt => &["Password", "Options"],
+        InputTo::ChangePassword => &["Old password", "New password", "Keyfile"],
+        InputTo::OpenVault => &["Vault", "Directory"],
+        InputTo::Recover | InputTo::CreateVault => &["Password", "Repeat"],
+        _ => &[],
+    }
+}
+
+/* Reads the keyfile if a path was typed in */
+fn read_keyfile(path: Option<&str>) -> Result<Option<Keyfile>> {
+    match path.map(str::trim).filter(|p| !p.is_empty()) {
+        Some(path) => Ok(Some(Keyfile::read(path)?)),
+        None => Ok(None),
+    }
+}
+
+/* Separates the keyfile=<path> option from the options of the encryption scheme */
+fn take_keyfile(options: &str) -> (String, Option<&str>) {
+    let mut keyfile = None;
+    let options = options
+        .split(',')
+        .filter(|option| match option.trim().strip_prefix("keyfile=") {
+            Some(path) => {
+                keyfile = Some(path);
+                false
+            }
+            None => true,
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    (options, keyfile)
+}
+
+/* Splits input with two fields at the tab separating them */
+fn split_input(input: &str) -> (&str, Option<&str>) {
+    match input.split_once('\t') {
+        Some((first, second)) => (first, Some(second)),
+        None => (input, None),
+    }
+}
+
 /* Draws a centered rectangle on r with the given width and height in percentage */
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     let popup_layout = Layout::default()
@@ -328,6 +479,26 @@ fn draw_command_list<B: Backend>(f: &mut Frame<B>, area: Rect, app: &mut ModuleU
             Span::styled("e", style),
             Span::raw("(ncrypt): Encrypt module"),
         ]),
+        Spans::from(vec![
+            Span::styled("p", style),
+            Span::raw("(assword): Change module password"),
+        ]),
+        Spans::from(vec![
+            Span::styled("v", style),
+            Span::raw("(ault): Unlock modules with the master password"),
+        ]),
+        Spans::from(vec![
+            Span::styled("k", style),
+            Span::raw("(eyring): Add or remove module from vault keyring"),
+        ]),
+        Spans::from(vec![
+            Span::styled("o", style),
+            Span::raw("(pen): Open or register a vault"),
+        ]),
+        Spans::from(vec![
+            Span::styled("h", style),
+            Span::raw("(ide): Hide or reveal module name on disk"),
+        ]),
         Spans::from(vec![
             Span::styled("d", style),
             Span::raw("(elete): Delete selected password"),
@@ -377,34 +548,11 @@ fn draw_command_list<B: Backend>(f: &mut Frame<B>, area: Rect, app: &mut ModuleU
 }
 /* Reads an unencrypted module from the corresponding file */
 pub fn read_unencrypted_module<'a>(
+    store: &dyn VaultStore,
     m: &mut (Cow<'a, str>, Option<PasswordEntries<'a>>),
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut base_path = PathBuf::from(env::var(HOME_ENV)?);
-    base_path.push(".pwmanager");
+) -> Result<()> {
     let f_name: &str = m.0.borrow();
-    let file = base_path.join(format!("{}.json", f_name));
-    let file = file.to_string_lossy();
-
-    m.1 = Some(read_from_file(Some(file.borrow()))?);
-    Ok(())
-}
-/* Reads a module from an encrypted file.*/
-pub fn read_encrypted_module<'a>(
-    list: &mut HashMap<Cow<'a, str>, EncryptionScheme>,
-    password: &str,
-    m: &mut (Cow<'a, str>, Option<PasswordEntries<'a>>),
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut base_path = PathBuf::from(env::var(HOME_ENV)?);
-    base_path.push(".pwmanager");
-    let f_name: &str = m.0.borrow();
-    let file = base_path.join(format!("{}.json", f_name));
-    let file = file.to_string_lossy();
-
-    if let Some(ec) = list.get(&m.0) {
-        ec.decrypt_file(password, &file)?;
-        let et = read_from_file(Some(&file))?;
-        m.1 = Some(et);
-    }
+    m.1 = Some(payload::decode(&read_module_file(store, f_name)?)?);
     Ok(())
 }
 /* Displays the passwords of the selected module */
@@ -448,30 +596,52 @@ fn draw_module_selected<B: Backend>(f: &mut Frame<B>, area: Rect, app: &mut Modu
     }
 }
 
-/* Encrypts all files that were decrypted. Resets all inputs, passwords and modules to prevent
- * leakage */
-pub fn clean_up(app: &mut ModuleUI) -> Result<(), Box<dyn std::error::Error>> {
-    let base_path = PathBuf::from(env::var(HOME_ENV)?);
-    for row in app.module_list.modules.iter_mut() {
-        let name = row.0.borrow();
-        if let Some(et) = row.1.as_mut() {
-            ModuleList::write_module(name, et)?;
-            if let Some(ec) = app.module_list.encryptions.get(&row.0) {
-                if let Some(pw) = app.passwords.get(name) {
-                    let file_name = base_path.join(format!(".pwmanager/{}.json", name));
-                    let file_name = file_name.to_string_lossy();
-                    ec.encrypt_file(pw, &file_name)?;
+/* Saves all loaded modules, encrypting them in memory when they have a scheme. Resets all inputs,
+ * keys and modules to prevent leakage. Dropping them wipes the secrets from memory */
+pub fn clean_up(app: &mut ModuleUI) -> Result<()> {
+    // Changes to a read-only vault are dropped, the secrets are still wiped
+    if !app.module_list.store.read_only() {
+        let store = &*app.module_list.store;
+        for row in app.module_list.modules.iter_mut() {
+            let name = row.0.borrow();
+            if let Some(et) = row.1.as_mut() {
+                match app.module_list.encryptions.get_mut(&row.0) {
+                    Some(ec) => {
+                        // Without the data key the file on disk is left as it is. Writing the
+                        // entries unencrypted would leak them.
+                        if let Some(key) = app.keys.get(name) {
+                            ModuleList::write_encrypted_module(store, name, ec, key, et)?;
+                            app.module_list.legacy.remove(name);
+                        }
+                    }
+                    None => ModuleList::write_module(store, name, et)?,
                 }
             }
         }
+        // Only modules that were not migrated to the container format still need
+        // encryptions.ron
+        let enc_path = ENCRYPTIONS_FILE;
+        let legacy: HashMap<_, _> = app
+            .module_list
+            .encryptions
+            .iter()
+            .filter(|(name, _)| app.module_list.legacy.contains(*name))
+            .collect();
+        if legacy.is_empty() {
+            if store.exists(enc_path)? {
+                store.remove(enc_path)?;
+            }
+        } else {
+            store.write(enc_path, ron::ser::to_string(&legacy)?.as_bytes())?;
+        }
     }
-    let mut enc_file = File::create(base_path.join(".pwmanager/encryptions.ron"))?;
-    ron::ser::to_writer(enc_file, &app.module_list.encryptions)?;
-
-    app.input_string = String::new();
-    app.passwords = HashMap::new();
+    app.input_string.zeroize();
+    app.keys = HashMap::new();
+    app.keyring = None;
     app.module_list.modules = Vec::new();
     app.module_list.encryptions = HashMap::new();
+    app.module_list.legacy = HashSet::new();
+    app.module_list.names = HashMap::new();
     Ok(())
 }
 
@@ -484,21 +654,205 @@ pub fn display_error<B: Backend>(f: &mut Frame<B>, area: Rect, message: &str) {
     f.render_widget(Clear, area);
     f.render_widget(p, area);
 }
-/* Runs the app */
+/* Unlocks the vault keyring with the typed master password, or creates it if the vault has
+ * none, and unlocks every module whose key it holds */
+fn unlock_vault(app: &mut ModuleUI) -> Result<()> {
+    let store = &*app.module_list.store;
+    // The password was typed twice to create the keyring
+    let password = match app.input_to {
+        InputTo::CreateVault => split_input(&app.input_string).0,
+        _ => &app.input_string,
+    };
+    let keyring = if Keyring::exists(store)? {
+        Keyring::unlock(store, password)?
+    } else if app.input_to == InputTo::CreateVault {
+        Keyring::create(store, password, EncryptionScheme::default())?
+    } else {
+        return Err(Error::invalid("The vault has no keyring"));
+    };
+    for (id, name) in keyring.names() {
+        app.module_list
+            .names
+            .insert(Cow::Owned(id.to_owned()), name.to_owned());
+    }
+    let mut failed = Vec::new();
+    for i in 0..app.module_list.modules.len() {
+        let name = app.module_list.modules[i].0.clone();
+        if app.module_list.modules[i].1.is_some() {
+            continue;
+        }
+        if let Some(key) = keyring.get(&name) {
+            match app.module_list.unlock_module_with_key(i, key) {
+                Ok(()) => {
+                    app.keys.insert(name, key.clone());
+                }
+                Err(e) => failed.push(format!("{}: {}", app.module_list.display_name(&name), e)),
+            }
+        }
+    }
+    app.keyring = Some(keyring);
+    if !failed.is_empty() {
+        return Err(Error::invalid(format!(
+            "Failed to unlock modules\n{}",
+            failed.join("\n")
+        )));
+    }
+    Ok(())
+}
+
+/* Adds the selected module to the vault keyring or removes it. Removed modules are only
+ * unlocked with their own password */
+fn toggle_keyring(app: &mut ModuleUI) -> Result<()> {
+    let keyring = app
+        .keyring
+        .as_mut()
+        .ok_or_else(|| Error::invalid("Unlock the vault first"))?;
+    let name = app
+        .module_index
+        .and_then(|i| app.module_list.modules.get(i))
+        .map(|m| m.0.clone())
+        .ok_or_else(|| Error::invalid("No module selected"))?;
+    if !keyring.remove(&name) {
+        let key = app.keys.get(&name).ok_or_else(|| {
+            Error::invalid("Only unlocked encrypted modules can be added to the vault")
+        })?;
+        let ec = app.module_list.encryptions.get(&name);
+        if ec.is_some_and(|ec| ec.keyfile.is_some()) {
+            return Err(Error::invalid(
+                "Modules that require a keyfile can't be added to the vault, the master password \
+                 alone would unlock them",
+            ));
+        }
+        keyring.insert(&name, key);
+    }
+    keyring.write(&*app.module_list.store)
+}
+
+/* Takes a module out of the keyring, e.g. before it gets a keyfile. Fails if the keyring is
+ * locked, as it could hold the module */
+fn leave_keyring(keyring: Option<&mut Keyring>, store: &dyn VaultStore, name: &str) -> Result<()> {
+    match keyring {
+        Some(keyring) => {
+            if keyring.remove(name) {
+                keyring.write(store)?;
+            }
+            Ok(())
+        }
+        None if Keyring::exists(store)? => Err(Error::invalid(
+            "Unlock the vault first, so the module can be taken out of it before it gets a keyfile",
+        )),
+        None => Ok(()),
+    }
+}
+
+/* Stores the selected module under a random ID and records its name in the vault index, or
+ * stores a hidden module under its name again. The index is written first, so a crash in between
+ * leaves an unused entry instead of a module nobody knows the name of */
+fn toggle_hidden(app: &mut ModuleUI) -> Result<()> {
+    let keyring = app
+        .keyring
+        .as_mut()
+        .ok_or_else(|| Error::invalid("Unlock the vault first"))?;
+    let i = app
+        .module_index
+        .filter(|i| *i < app.module_list.modules.len())
+        .ok_or_else(|| Error::invalid("No module selected"))?;
+    let old = app.module_list.modules[i].0.clone();
+    let (new, name) = match keyring.name(&old) {
+        Some(name) => (name.to_owned(), None),
+        None => (new_hidden_id(), Some(old.to_string())),
+    };
+    keyring.rename(&old, &new);
+    keyring.set_name(&new, name.as_deref());
+    keyring.set_name(&old, None);
+    keyring.write(&*app.module_list.store)?;
+
+    if let Err(e) = app.module_list.rename_module(i, &new, app.keys.get(&old)) {
+        keyring.rename(&new, &old);
+        keyring.set_name(&old, name.is_none().then_some(new.as_str()));
+        keyring.set_name(&new, None);
+        keyring.write(&*app.module_list.store)?;
+        return Err(e);
+    }
+    if let Some(key) = app.keys.remove(&old) {
+        app.keys.insert(Cow::Owned(new.clone()), key);
+    }
+    app.module_list.names.remove(&old);
+    if let Some(name) = name {
+        app.module_list.names.insert(Cow::Owned(new), name);
+    }
+    Ok(())
+}
+
+/* Saves the open vault and opens the typed one, registering it under the name if a directory is
+ * typed as well. The keyring of the new vault is asked for right away */
+fn open_vault(app: &mut ModuleUI) -> Result<()> {
+    let (vault, dir) = split_input(&app.input_string);
+    let vault = vault.trim().to_owned();
+    if let Some(dir) = dir.map(str::trim).filter(|dir| !dir.is_empty()) {
+        app.vaults.insert(&vault, PathBuf::from(dir));
+        app.vaults.save()?;
+    }
+    // A vault opened while another one is read-only is read-only too
+    let read_only = app.module_list.store.read_only();
+    let store = FsStore::new(app.vaults.resolve(&vault)?, read_only)?;
+    let module_list = ModuleList::open(Box::new(store))?;
+
+    clean_up(app)?;
+    app.set_items(module_list);
+    app.vault = vault;
+    app.module_index = None;
+    app.display_module = false;
+    app.table_state = TableState::default();
+    app.table_key = None;
+    app.selection = Selection::Modules;
+    app.input_to = InputTo::Nothing;
+    if !app.prompt_recover() && Keyring::exists(&*app.module_list.store)? {
+        app.prompt_vault();
+    }
+    Ok(())
+}
+
+/* Runs the app until it is quit or exit is set by a signal. If the app panics the vault is still
+ * saved and the secrets are wiped before the panic continues */
 pub fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
     mut app: ModuleUI,
-) -> Result<(), Box<dyn std::error::Error>> {
+    exit: &AtomicBool,
+) -> Result<()> {
+    match panic::catch_unwind(AssertUnwindSafe(|| event_loop(terminal, &mut app, exit))) {
+        Ok(result) => result,
+        Err(payload) => {
+            if let Err(e) = clean_up(&mut app) {
+                eprintln!("Failed to save the vault: {}", e);
+            }
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+fn event_loop<B: Backend>(
+    terminal: &mut Terminal<B>,
+    app: &mut ModuleUI,
+    exit: &AtomicBool,
+) -> Result<()> {
     loop {
-        terminal.draw(|f| ui(f, &mut app)).map_err(|e| {
-            clean_up(&mut app).expect("Failed to clean up on error");
-            e
-        })?;
+        if exit.load(Ordering::Relaxed) {
+            return clean_up(app);
+        }
+        if let Err(e) = terminal.draw(|f| ui(f, app)) {
+            clean_up(app)?;
+            return Err(e.into());
+        }
 
+        // Waiting for input only until POLL_INTERVAL lets a signal end the app
+        if !event::poll(POLL_INTERVAL)? {
+            continue;
+        }
         if let Event::Key(key) = event::read()? {
             match app.input_mode {
                 InputMode::Normal => match key.code {
-                    KeyCode::Char('q') => return clean_up(&mut app),
+                    KeyCode::Char('q') => return clean_up(app),
                     KeyCode::Down => match app.selection {
                         Selection::Modules => app.next(),
                         Selection::Passwords => app.next_password(),
@@ -522,6 +876,16 @@ pub fn run_app<B: Backend>(
                                 app.display_module = true;
                                 continue;
                             }
+                            if let Some(reason) = app.module_list.unreadable.get(&m.0) {
+                                app.display_error = true;
+                                app.error_message = format!("Module can't be read: {}", reason);
+                                continue;
+                            }
+                            if app.module_list.exposed.contains(&m.0) {
+                                app.input_mode = InputMode::Inputing;
+                                app.input_to = InputTo::Recover;
+                                continue;
+                            }
 
                             match app.module_list.encryptions.get(&m.0) {
                                 Some(_) => {
@@ -529,7 +893,9 @@ pub fn run_app<B: Backend>(
                                     app.input_to = InputTo::Decrypt;
                                 }
                                 None => {
-                                    if let Err(e) = read_unencrypted_module(m) {
+                                    if let Err(e) =
+                                        read_unencrypted_module(&*app.module_list.store, m)
+                                    {
                                         app.display_error = true;
                                         app.error_message = e.to_string();
                                     } else {
@@ -551,6 +917,27 @@ pub fn run_app<B: Backend>(
                         app.input_mode = InputMode::Inputing;
                         app.input_to = InputTo::Encrypt;
                     }
+                    KeyCode::Char('p') => {
+                        app.input_mode = InputMode::Inputing;
+                        app.input_to = InputTo::ChangePassword;
+                    }
+                    KeyCode::Char('v') => app.prompt_vault(),
+                    KeyCode::Char('o') => {
+                        app.input_mode = InputMode::Inputing;
+                        app.input_to = InputTo::OpenVault;
+                    }
+                    KeyCode::Char('k') => {
+                        if let Err(e) = toggle_keyring(app) {
+                            app.display_error = true;
+                            app.error_message = e.to_string();
+                        }
+                    }
+                    KeyCode::Char('h') => {
+                        if let Err(e) = toggle_hidden(app) {
+                            app.display_error = true;
+                            app.error_message = e.to_string();
+                        }
+                    }
                     KeyCode::Right => {
                         app.selection = Selection::Passwords;
                     }
@@ -569,6 +956,8 @@ pub fn run_app<B: Backend>(
                                     et.remove(k);
                                 }
                             }
+                            // Set again for the row selected after the next draw, if any
+                            app.table_key = None;
                         } else {
                             app.display_error = true;
                             app.error_message = "No selection found".to_owned();
@@ -581,20 +970,34 @@ pub fn run_app<B: Backend>(
                                 .and_then(|i| app.module_list.modules.get(i))
                                 .and_then(|m| m.1.as_ref())
                             {
-                                let pw = et.get(k).unwrap();
-                                terminal_clipboard::set_string(pw.get()).map_err(|e| {
-                                    io::Error::new(io::ErrorKind::Other, e.to_string())
-                                })?;
+                                match et.get(k) {
+                                    Some(pw) => {
+                                        if let Err(e) = terminal_clipboard::set_string(pw.get()) {
+                                            app.display_error = true;
+                                            app.error_message =
+                                                format!("Failed to copy the password: {}", e);
+                                        }
+                                    }
+                                    None => {
+                                        app.display_error = true;
+                                        app.error_message = "No password selected".to_owned();
+                                    }
+                                }
                             }
                         }
                     }
                     _ => {}
                 },
                 InputMode::Inputing => match key.code {
-                    KeyCode::Char(c) => app.input_string.push(c),
-                    KeyCode::Tab => {
-                        app.input_string.push(' ');
-                        app.input_string.push('c')
+                    KeyCode::Char(c) => {
+                        app.display_error = false;
+                        app.input_string.push(c)
+                    }
+                    KeyCode::Tab
+                        if app.input_string.matches('\t').count() + 1
+                            < input_fields(&app.input_to).len() =>
+                    {
+                        app.input_string.push('\t')
                     }
                     KeyCode::Esc => {
                         app.input_mode = InputMode::Normal;
@@ -611,8 +1014,8 @@ pub fn run_app<B: Backend>(
                                 {
                                     let pw = Password::new_password32();
                                     let entry = m.1.get_or_insert(PasswordEntries::new());
-                                    entry.insert(Cow::Owned(app.input_string.clone()), pw);
-                                    app.input_string = String::new();
+                                    entry.insert(Cow::Owned(app.input_string.to_string()), pw);
+                                    app.input_string.zeroize();
                                     app.input_to = InputTo::Nothing;
                                 } else {
                                     app.display_error = true;
@@ -624,14 +1027,13 @@ pub fn run_app<B: Backend>(
                                     .module_index
                                     .and_then(|i| app.module_list.modules.get_mut(i))
                                 {
-                                    let mut iter = app.input_string.split_whitespace();
-                                    if let Some(name) = iter.next() {
-                                        if let Some(pw) = iter.next() {
-                                            let pw = pw.get(1..).unwrap_or("");
+                                    let (name, pw) = split_input(&app.input_string);
+                                    if !name.is_empty() {
+                                        if let Some(pw) = pw.filter(|pw| !pw.is_empty()) {
                                             let password = Password::new_from(pw);
                                             let entry = m.1.get_or_insert(PasswordEntries::new());
                                             entry.insert(Cow::Owned(name.to_owned()), password);
-                                            app.input_string = String::new();
+                                            app.input_string.zeroize();
                                             app.input_to = InputTo::Nothing;
                                         } else {
                                             app.display_error = true;
@@ -644,57 +1046,233 @@ pub fn run_app<B: Backend>(
                                 }
                             }
                             InputTo::Decrypt => {
-                                if let Some(m) = app
+                                if let Some(i) = app
                                     .module_index
-                                    .and_then(|i| app.module_list.modules.get_mut(i))
+                                    .filter(|i| *i < app.module_list.modules.len())
+                                {
+                                    let (password, keyfile) = split_input(&app.input_string);
+                                    match read_keyfile(keyfile).and_then(|keyfile| {
+                                        app.module_list.unlock_module(i, password, keyfile.as_ref())
+                                    }) {
+                                        Ok(key) => {
+                                            app.display_module = true;
+                                            let name = app.module_list.modules[i].0.clone();
+                                            app.keys.insert(name, key);
+                                            app.input_to = InputTo::Nothing;
+                                        }
+                                        Err(e) => app.show_error(e),
+                                    }
+                                    app.input_string.zeroize();
+                                }
+                            }
+                            InputTo::Recover => {
+                                if let Some(i) = app
+                                    .module_index
+                                    .filter(|i| *i < app.module_list.modules.len())
                                 {
-                                    if let Err(e) = read_encrypted_module(
-                                        &mut app.module_list.encryptions,
-                                        &app.input_string,
-                                        m,
-                                    ) {
+                                    // Modules without a data key take any password, a typo would
+                                    // lock them for good
+                                    let (password, repeat) = split_input(&app.input_string);
+                                    if repeat != Some(password) {
                                         app.display_error = true;
-                                        app.error_message = e.to_string();
-
-                                        app.error_message
-                                            .push_str("\nMost likely wrong password\n");
-                                    } else {
-                                        app.display_module = true;
-                                        app.passwords
-                                            .insert(m.0.to_owned(), app.input_string.clone());
+                                        app.error_message = "The passwords don't match".to_owned();
+                                        app.input_mode = InputMode::Inputing;
+                                        app.input_string.zeroize();
+                                        continue;
                                     }
-                                    app.input_to = InputTo::Nothing;
-                                    app.input_mode = InputMode::Normal;
-                                    app.input_string = String::new();
+                                    match app.module_list.recover_module(i, password) {
+                                        Ok(key) => {
+                                            let name = app.module_list.modules[i].0.clone();
+                                            app.keys.insert(name, key);
+                                            app.input_to = InputTo::Nothing;
+                                            if !app.prompt_recover() {
+                                                app.display_module = true;
+                                            }
+                                        }
+                                        Err(e) => app.show_error(e),
+                                    }
+                                    app.input_string.zeroize();
                                 }
                             }
+                            InputTo::ChangePassword => {
+                                if let Some(i) = app
+                                    .module_index
+                                    .filter(|i| *i < app.module_list.modules.len())
+                                {
+                                    let (old, rest) = split_input(&app.input_string);
+                                    let (new, keyfile) = match rest.map(split_input) {
+                                        Some((new, keyfile)) => (Some(new), keyfile),
+                                        None => (None, None),
+                                    };
+                                    // A module that gets a keyfile leaves the keyring first,
+                                    // the master password alone would unlock it otherwise
+                                    let name = app.module_list.modules[i].0.clone();
+                                    let adds_keyfile = keyfile
+                                        .is_some_and(|path| !path.trim().is_empty())
+                                        && app
+                                            .module_list
+                                            .encryptions
+                                            .get(&name)
+                                            .is_some_and(|ec| ec.keyfile.is_none());
+                                    let changed = match new.filter(|new| !new.is_empty()) {
+                                        Some(new) => read_keyfile(keyfile).and_then(|keyfile| {
+                                            if adds_keyfile {
+                                                leave_keyring(
+                                                    app.keyring.as_mut(),
+                                                    &*app.module_list.store,
+                                                    &name,
+                                                )?;
+                                            }
+                                            app.module_list.change_password(
+                                                i,
+                                                old,
+                                                new,
+                                                keyfile.as_ref(),
+                                            )
+                                        }),
+                                        None => {
+                                            Err(Error::invalid("Please enter the new password"))
+                                        }
+                                    };
+                                    match changed {
+                                        Ok(key) => {
+                                            app.display_module = true;
+                                            app.keys.insert(name, key);
+                                            app.input_to = InputTo::Nothing;
+                                        }
+                                        Err(e) => app.show_error(e),
+                                    }
+                                    app.input_string.zeroize();
+                                } else {
+                                    app.display_error = true;
+                                    app.error_message = "No module selected".to_owned();
+                                }
+                            }
+                            InputTo::Vault | InputTo::CreateVault => {
+                                // A typo would make the keyring and the index of hidden modules
+                                // unrecoverable
+                                if app.input_to == InputTo::CreateVault {
+                                    let (password, repeat) = split_input(&app.input_string);
+                                    if repeat != Some(password) {
+                                        app.display_error = true;
+                                        app.error_message = "The passwords don't match".to_owned();
+                                        app.input_mode = InputMode::Inputing;
+                                        app.input_string.zeroize();
+                                        continue;
+                                    }
+                                }
+                                match unlock_vault(app) {
+                                    Ok(()) => app.input_to = InputTo::Nothing,
+                                    Err(e) => app.show_error(e),
+                                }
+                                app.input_string.zeroize();
+                            }
+                            InputTo::OpenVault => {
+                                if let Err(e) = open_vault(app) {
+                                    app.show_error(e);
+                                }
+                                app.input_string.zeroize();
+                            }
                             InputTo::Module => {
                                 let entries = PasswordEntries::new();
-                                app.module_list.add_module(&app.input_string, entries)?;
-                                app.input_string = String::new();
-                                app.input_to = InputTo::Nothing;
+                                match app.module_list.add_module(&app.input_string, entries) {
+                                    Ok(()) => app.input_to = InputTo::Nothing,
+                                    Err(e) => app.show_error(e),
+                                }
+                                app.input_string.zeroize();
                             }
                             InputTo::Encrypt => {
                                 if let Some(m) = app
                                     .module_index
-                                    .and_then(|i| app.module_list.modules.get(i))
+                                    .and_then(|i| app.module_list.modules.get_mut(i))
                                 {
-                                    if (app.input_string.is_empty()) {
+                                    // A new scheme would replace the password without checking the
+                                    // old one and leave the keyring with a stale data key
+                                    if app.module_list.encryptions.contains_key(&m.0) {
+                                        app.display_error = true;
+                                        app.error_message =
+                                            "Module is already encrypted, change its password with p"
+                                                .to_owned();
+                                        app.input_string.zeroize();
+                                        app.input_to = InputTo::Nothing;
+                                        continue;
+                                    }
+                                    let (password, options) = split_input(&app.input_string);
+                                    let (options, keyfile) = take_keyfile(options.unwrap_or(""));
+                                    if password.is_empty() {
                                         app.display_error = true;
                                         app.error_message = "Please enter a password".to_owned();
+                                        app.input_string.zeroize();
+                                        continue;
+                                    }
+                                    // The module is only written encrypted if it is loaded
+                                    if m.1.is_none() {
+                                        if let Err(e) =
+                                            read_unencrypted_module(&*app.module_list.store, m)
+                                        {
+                                            app.display_error = true;
+                                            app.error_message = e.to_string();
+                                            app.input_string.zeroize();
+                                            continue;
+                                        }
+                                    }
+                                    // The data key is generated and wrapped right away, so only
+                                    // the key has to be kept until the module is saved
+                                    let mut ec = match EncryptionScheme::from_options(&options) {
+                                        Ok(ec) => ec,
+                                        Err(e) => {
+                                            app.display_error = true;
+                                            app.error_message = e.to_string();
+                                            app.input_string.zeroize();
+                                            continue;
+                                        }
+                                    };
+                                    let key = match read_keyfile(keyfile).and_then(|keyfile| {
+                                        ec.unlock_or_generate(password, keyfile.as_ref())
+                                    }) {
+                                        Ok(key) => key,
+                                        Err(e) => {
+                                            app.display_error = true;
+                                            app.error_message = e.to_string();
+                                            app.input_string.zeroize();
+                                            continue;
+                                        }
+                                    };
+
+                                    // The plaintext file is wiped right away, a crash before
+                                    // quitting would leave the module unencrypted otherwise
+                                    let store = &*app.module_list.store;
+                                    let written = match m.1.as_ref() {
+                                        Some(et) if !store.read_only() => {
+                                            ModuleList::write_encrypted_module(
+                                                store, &m.0, &mut ec, &key, et,
+                                            )
+                                        }
+                                        _ => Ok(()),
+                                    };
+                                    if let Err(e) = written {
+                                        app.display_error = true;
+                                        app.error_message = e.to_string();
+                                        app.input_string.zeroize();
                                         continue;
                                     }
-                                    let base_path = env::var(HOME_ENV)?;
-                                    let name: &str = m.0.borrow();
-                                    let file = format!("{}\\.pwmanager\\{}.json", base_path, name);
-                                    let ec = EncryptionScheme::default();
 
-                                    //create_and_save_to_der(&der_file)?;
-                                    //m.1 = Some(Cow::Owned(der_file.to_owned()));
+                                    // Modules encrypted while the vault is unlocked join its
+                                    // keyring. They can be taken out again with k. Modules that
+                                    // require a keyfile stay out, the master password alone
+                                    // would unlock them
+                                    if let Some(keyring) =
+                                        app.keyring.as_mut().filter(|_| ec.keyfile.is_none())
+                                    {
+                                        keyring.insert(&m.0, &key);
+                                        if let Err(e) = keyring.write(&*app.module_list.store) {
+                                            app.display_error = true;
+                                            app.error_message = e.to_string();
+                                        }
+                                    }
                                     app.module_list.encryptions.insert(m.0.to_owned(), ec);
-                                    app.passwords
-                                        .insert(m.0.to_owned(), app.input_string.clone());
-                                    app.input_string = String::new();
+                                    app.keys.insert(m.0.clone(), key);
+                                    app.input_string.zeroize();
                                     app.input_to = InputTo::Nothing;
                                 } else {
                                     app.display_error = true;
@@ -718,7 +1296,7 @@ pub fn run_app<B: Backend>(
 /* Calls the different UI functions and provides layout*/
 fn ui<B: Backend>(f: &mut Frame<B>, app: &mut ModuleUI) {
     let mod_size: u16 = app.module_list.modules.len() as u16;
-    let command_size: u16 = 7;
+    let command_size: u16 = 11;
     let v_chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Min(10), Constraint::Length(command_size + 2)])
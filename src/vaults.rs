@@ -0,0 +1,88 @@
+/*
+ * Locations of vaults. The vault opened on start is given with --vault, or else by the
+ * PWMANAGER_DIR environment variable, and defaults to the pwmanager directory in the home
+ * directory. Vaults can be registered under a name, e.g. a shared vault on a mounted drive, and
+ * are then opened by that name. The registry is kept in vaults.ron in the pwmanager directory of
+ * the home directory, whichever vault is open.
+ * */
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+use crate::error::{Error, Result};
+use crate::password::{write_atomic, HOME_ENV};
+
+/* Environment variable with the directory of the vault to open */
+pub const VAULT_DIR_ENV: &str = "PWMANAGER_DIR";
+/* File of the registered vaults in the pwmanager directory */
+pub const VAULTS_FILE: &str = "vaults.ron";
+
+/* Pwmanager directory in the home directory */
+fn home_dir() -> Result<PathBuf> {
+    let mut dir = PathBuf::from(env::var(HOME_ENV)?);
+    dir.push(".pwmanager");
+    Ok(dir)
+}
+
+/* Directory of the vault opened when none is given on the command line */
+pub fn default_dir() -> Result<PathBuf> {
+    match env::var_os(VAULT_DIR_ENV) {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => home_dir(),
+    }
+}
+
+/* Registered vaults by name */
+#[derive(Default)]
+pub struct Vaults {
+    dirs: BTreeMap<String, PathBuf>,
+}
+
+impl Vaults {
+    /* Reads the registered vaults. There are none if the registry doesn't exist yet */
+    pub fn load() -> Result<Self> {
+        let content = match fs::read(home_dir()?.join(VAULTS_FILE)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            dirs: ron::de::from_bytes(&content)?,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let dir = home_dir()?;
+        fs::create_dir_all(&dir)?;
+        write_atomic(
+            &dir.join(VAULTS_FILE),
+            ron::ser::to_string(&self.dirs)?.as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /* Registers the vault in dir under name, replacing a vault of the same name */
+    pub fn insert(&mut self, name: &str, dir: PathBuf) {
+        self.dirs.insert(name.to_owned(), dir);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.dirs.keys().map(String::as_str)
+    }
+
+    /* Directory of the vault given by its name or its directory. Anything that is neither a
+     * registered name nor looks like a path is rejected, so a mistyped name doesn't create a new
+     * vault in the working directory */
+    pub fn resolve(&self, vault: &str) -> Result<PathBuf> {
+        if let Some(dir) = self.dirs.get(vault) {
+            return Ok(dir.clone());
+        }
+        if vault.contains(MAIN_SEPARATOR) || vault.contains('/') || Path::new(vault).is_dir() {
+            return Ok(PathBuf::from(vault));
+        }
+        Err(Error::invalid(format!("No vault named {}", vault)))
+    }
+}